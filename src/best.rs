@@ -0,0 +1,113 @@
+/// Writing the best bind to a file, for scripts that use it
+use std::{fs, path::Path};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::history::{Record, TargetResult};
+
+#[derive(Debug, ValueEnum, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BestFormat {
+    /// Only the address
+    Plain,
+    /// `BESTBIND_BEST=<address>` and other details, can be sourced by shell
+    Env,
+    /// A JSON object
+    Json,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BestJson {
+    network: String,
+    comment: String,
+    bandwidth: f64, // KB/s
+    profile: String,
+    upstream: String,
+    time: u64,
+}
+
+const ENV_KEY: &str = "BESTBIND_BEST";
+
+fn env_line(key: &str, value: &str) -> String {
+    let value =
+        shlex::try_quote(value).map_or_else(|_| String::new(), std::borrow::Cow::into_owned);
+    format!("{key}={value}\n")
+}
+
+fn render(format: BestFormat, record: &Record, best: &TargetResult) -> Result<String> {
+    Ok(match format {
+        BestFormat::Plain => format!("{}\n", best.network),
+        BestFormat::Env => [
+            env_line(ENV_KEY, &best.network),
+            env_line(&format!("{ENV_KEY}_COMMENT"), &best.comment),
+            env_line(&format!("{ENV_KEY}_BANDWIDTH"), &best.score.to_string()),
+            env_line(&format!("{ENV_KEY}_PROFILE"), &record.profile),
+            env_line(&format!("{ENV_KEY}_UPSTREAM"), &record.upstream),
+        ]
+        .concat(),
+        BestFormat::Json => {
+            let json = BestJson {
+                network: best.network.clone(),
+                comment: best.comment.clone(),
+                bandwidth: best.score,
+                profile: record.profile.clone(),
+                upstream: record.upstream.clone(),
+                time: record.time,
+            };
+            serde_json::to_string_pretty(&json)? + "\n"
+        }
+    })
+}
+
+/// Address in a file written before, None if it is missing or cannot be parsed
+fn current(path: &Path, format: BestFormat) -> Option<String> {
+    let content = fs::read_to_string(path).ok()?;
+    let network = match format {
+        BestFormat::Plain => content.trim().to_string(),
+        BestFormat::Env => content.lines().find_map(|line| {
+            let value = line.strip_prefix(ENV_KEY)?.strip_prefix('=')?;
+            shlex::split(value)?.into_iter().next()
+        })?,
+        BestFormat::Json => serde_json::from_str::<BestJson>(&content).ok()?.network,
+    };
+    (!network.is_empty()).then_some(network)
+}
+
+/// Write the best bind among records to `path`, via a temp file and rename.
+/// Nothing is written if no target has a positive score, or if the address in file
+/// is tested in the same upstream and the best one is not faster by `margin` percent.
+pub fn write(path: &Path, format: BestFormat, margin: f64, records: &[Record]) -> Result<()> {
+    let Some((record, best)) = records
+        .iter()
+        .filter_map(|record| record.best().map(|best| (record, best)))
+        .max_by(|a, b| a.1.score.partial_cmp(&b.1.score).unwrap())
+        .filter(|(_, best)| best.score > 0.0)
+    else {
+        println!("No valid best bind, {} is not written", path.display());
+        return Ok(());
+    };
+
+    if let Some(current) = current(path, format) {
+        if let Some(old) = record.targets.iter().find(|t| t.network == current) {
+            if old.network != best.network && best.score <= old.score * (1.0 + margin / 100.0) {
+                println!(
+                    "Keeping {current} in {}: {} ({}) is not faster by more than {margin}%",
+                    path.display(),
+                    best.network,
+                    best.comment
+                );
+                return Ok(());
+            }
+        }
+    }
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, render(format, record, best)?)
+        .with_context(|| format!("Cannot write {}", Path::new(&tmp).display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("Cannot write {}", path.display()))?;
+    println!("Best bind {} written to {}", best.network, path.display());
+    Ok(())
+}
@@ -0,0 +1,119 @@
+/// Bar chart of final results in terminal
+use std::env;
+
+use crate::{
+    history::{Outcome, TargetResult},
+    unit::Unit,
+};
+
+const BLOCKS: [char; 8] = ['▏', '▎', '▍', '▌', '▋', '▊', '▉', '█'];
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+// Shown in sparkline for failed passes
+const FAILED: char = '×';
+
+const GREEN: &str = "\x1b[32m";
+const CYAN: &str = "\x1b[36m";
+const RED: &str = "\x1b[31m";
+const RESET: &str = "\x1b[0m";
+
+/// Width of terminal, from COLUMNS or the tty of stdout, default to 80
+fn terminal_width() -> usize {
+    if let Some(columns) = env::var("COLUMNS").ok().and_then(|c| c.parse().ok()) {
+        return columns;
+    }
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &raw mut size) } == 0
+        && size.ws_col > 0
+    {
+        return size.ws_col.into();
+    }
+    80
+}
+
+/// Colors are used only in terminal, and unless disabled by `NO_COLOR` (<https://no-color.org/>)
+fn use_color() -> bool {
+    env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+        && unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1
+}
+
+fn paint(s: &str, color: &str, enabled: bool) -> String {
+    if enabled {
+        format!("{color}{s}{RESET}")
+    } else {
+        s.to_string()
+    }
+}
+
+/// Bar of `value / max` of `width` characters, in eighths
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)] // value is in (0, max]
+fn bar(value: f64, max: f64, width: usize) -> String {
+    if max <= 0.0 || value <= 0.0 {
+        return String::new();
+    }
+    let eighths = (value / max * (width * 8) as f64).round() as usize;
+    let mut bar = BLOCKS[7].to_string().repeat(eighths / 8);
+    if !eighths.is_multiple_of(8) {
+        bar.push(BLOCKS[eighths % 8 - 1]);
+    }
+    bar
+}
+
+/// One character per pass, scaled to `max`
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)] // bandwidth is in [0, max]
+fn sparkline(result: &TargetResult, max: f64, color: bool) -> String {
+    result
+        .samples
+        .iter()
+        .map(|sample| {
+            if !sample.outcome.is_none_or(Outcome::counts) {
+                return paint(&FAILED.to_string(), RED, color);
+            }
+            let level = if max > 0.0 {
+                (sample.bandwidth / max * 7.0).round() as usize
+            } else {
+                0
+            };
+            SPARKS[level.min(7)].to_string()
+        })
+        .collect()
+}
+
+/// Print results (sorted, best first) as bars scaled to the best one,
+/// followed by a sparkline of passes
+pub fn print(results: &[TargetResult], unit: Unit) {
+    let Some(best) = results.first() else {
+        return;
+    };
+    let color = use_color();
+    let labels: Vec<_> = results
+        .iter()
+        .map(|result| format!("{} ({})", result.network, result.comment))
+        .collect();
+    let values: Vec<_> = results
+        .iter()
+        .map(|result| unit.format(result.score))
+        .collect();
+    let label_width = labels.iter().map(|l| l.chars().count()).max().unwrap_or(0);
+    let value_width = values.iter().map(String::len).max().unwrap_or(0);
+    let passes = results.iter().map(|r| r.samples.len()).max().unwrap_or(0);
+    let max_sample = results
+        .iter()
+        .flat_map(TargetResult::succeeded)
+        .map(|sample| sample.bandwidth)
+        .fold(0.0, f64::max);
+    // label, bar, value and sparkline separated by 2 spaces
+    let bar_width = terminal_width()
+        .saturating_sub(label_width + value_width + passes + 6)
+        .max(10);
+
+    println!("Chart (bars scaled to the best, then bandwidth of each pass):");
+    for (i, ((result, label), value)) in results.iter().zip(&labels).zip(&values).enumerate() {
+        let bar = bar(result.score, best.score, bar_width);
+        let padding = " ".repeat(bar_width - bar.chars().count());
+        let bar = paint(&bar, if i == 0 { GREEN } else { CYAN }, color);
+        println!(
+            "{label:label_width$}  {bar}{padding}  {value:>value_width$}  {}",
+            sparkline(result, max_sample, color)
+        );
+    }
+}
@@ -0,0 +1,714 @@
+/// Config file parsing, and resolving profiles from it
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize, Serializer};
+use toml::de::{DeTable, ValueDeserializer};
+use xdg::BaseDirectories;
+
+use crate::{best::BestFormat, hook::Hooks, FailurePolicy, Program};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    IP,
+    Docker,
+}
+
+impl<'de> Deserialize<'de> for Format {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s: String = Deserialize::deserialize(deserializer)?;
+        let s = s.to_lowercase();
+        match s.as_str() {
+            "ip" => Ok(Self::IP),
+            "docker" => Ok(Self::Docker),
+            _ => Err(serde::de::Error::custom(format!(
+                "Unknown format: {s}. Supported formats: ip, docker"
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::IP => "ip",
+            Self::Docker => "docker",
+        };
+        write!(f, "{s}")
+    }
+}
+
+impl Serialize for Format {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+fn default_docker() -> String {
+    "docker".to_string()
+}
+
+fn default_image() -> String {
+    "ghcr.io/taoky/bestbind-env:master".to_string()
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(untagged)]
+pub enum Use {
+    Comment(String),
+    Detailed(UseDetail),
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct UseDetail {
+    pub comment: String,
+    // Targets with the same tag share an uplink
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+}
+
+// Not derived with untagged, which hides errors in tables (like unknown keys)
+impl<'de> Deserialize<'de> for Use {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct UseVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for UseVisitor {
+            type Value = Use;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a comment, or a table with comment and tag")
+            }
+
+            fn visit_str<E>(self, comment: &str) -> Result<Use, E> {
+                Ok(Use::Comment(comment.to_string()))
+            }
+
+            fn visit_map<A>(self, map: A) -> Result<Use, A::Error>
+            where
+                A: serde::de::MapAccess<'de>,
+            {
+                UseDetail::deserialize(serde::de::value::MapAccessDeserializer::new(map))
+                    .map(Use::Detailed)
+            }
+        }
+
+        deserializer.deserialize_any(UseVisitor)
+    }
+}
+
+/// Retrying a test when the program fails with some exit codes
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Retry {
+    // Max times to retry
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub times: Option<usize>,
+    // Exit codes to retry on, replacing default ones of the program
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub codes: BTreeMap<Program, Vec<i32>>,
+}
+
+impl Retry {
+    /// Fill keys missing in self with those in base
+    fn or(self, base: Self) -> Self {
+        let mut codes = base.codes;
+        codes.extend(self.codes);
+        Self {
+            times: self.times.or(base.times),
+            codes,
+        }
+    }
+}
+
+/// Profile after resolving `extends` and `[defaults]`
+#[derive(Debug, Serialize, Clone)]
+pub struct Profile {
+    pub format: Format,
+    pub image: String,  // Docker image name, only used in Docker format
+    pub docker: String, // The "Docker" command, default to "docker".
+    // A possible alternative is "podman"
+    // Skip networks not found in Docker format, instead of aborting
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skip_missing_networks: Option<bool>,
+    // Options of the work directory volume in Docker format, like "Z" for SELinux
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub volume_options: Option<String>,
+    // User namespace of containers, like "keep-id" for rootless podman
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub userns: Option<String>,
+    // Copy results out of containers instead of mounting the work directory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub copy_results: Option<bool>,
+    #[serde(serialize_with = "sorted")]
+    pub uses: HashMap<String, Use>, // IP or Docker network => comment (and tag)
+    // Defaults of command line options, see Args for meanings
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub upstream: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub program: Option<Program>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pass: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extra: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tmp_dir: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log: Option<String>,
+    // Seconds between tests in daemon mode, and max random delay added to it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jitter: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write_best: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write_best_format: Option<BestFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write_best_margin: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hooks: Option<Hooks>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_failure: Option<FailurePolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry: Option<Retry>,
+}
+
+fn sorted<S>(uses: &HashMap<String, Use>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    uses.iter()
+        .collect::<BTreeMap<_, _>>()
+        .serialize(serializer)
+}
+
+/// Profile as written in config file. All keys are optional here:
+/// missing ones are taken from the profile it `extends`, then from `[defaults]`.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+struct RawProfile {
+    extends: Option<String>,
+    format: Option<Format>,
+    image: Option<String>,
+    docker: Option<String>,
+    skip_missing_networks: Option<bool>,
+    volume_options: Option<String>,
+    userns: Option<String>,
+    copy_results: Option<bool>,
+    uses: Option<HashMap<String, Use>>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    upstream: Option<Vec<String>>,
+    program: Option<Program>,
+    pass: Option<usize>,
+    timeout: Option<usize>,
+    extra: Option<String>,
+    tmp_dir: Option<String>,
+    log: Option<String>,
+    interval: Option<u64>,
+    jitter: Option<u64>,
+    write_best: Option<String>,
+    write_best_format: Option<BestFormat>,
+    write_best_margin: Option<f64>,
+    hooks: Option<Hooks>,
+    on_failure: Option<FailurePolicy>,
+    retry: Option<Retry>,
+}
+
+fn one_or_many<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(Some(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(s) => vec![s],
+        OneOrMany::Many(v) => v,
+    }))
+}
+
+impl RawProfile {
+    /// Fill keys missing in self with those in base.
+    /// `uses` tables are merged, with entries in self taking precedence.
+    fn or(self, base: Self) -> Self {
+        let uses = match (self.uses, base.uses) {
+            (Some(uses), Some(mut base_uses)) => {
+                base_uses.extend(uses);
+                Some(base_uses)
+            }
+            (uses, base_uses) => uses.or(base_uses),
+        };
+        Self {
+            extends: None,
+            format: self.format.or(base.format),
+            image: self.image.or(base.image),
+            docker: self.docker.or(base.docker),
+            skip_missing_networks: self.skip_missing_networks.or(base.skip_missing_networks),
+            volume_options: self.volume_options.or(base.volume_options),
+            userns: self.userns.or(base.userns),
+            copy_results: self.copy_results.or(base.copy_results),
+            uses,
+            upstream: self.upstream.or(base.upstream),
+            program: self.program.or(base.program),
+            pass: self.pass.or(base.pass),
+            timeout: self.timeout.or(base.timeout),
+            extra: self.extra.or(base.extra),
+            tmp_dir: self.tmp_dir.or(base.tmp_dir),
+            log: self.log.or(base.log),
+            interval: self.interval.or(base.interval),
+            jitter: self.jitter.or(base.jitter),
+            write_best: self.write_best.or(base.write_best),
+            write_best_format: self.write_best_format.or(base.write_best_format),
+            write_best_margin: self.write_best_margin.or(base.write_best_margin),
+            hooks: match (self.hooks, base.hooks) {
+                (Some(hooks), Some(base_hooks)) => Some(hooks.or(base_hooks)),
+                (hooks, base_hooks) => hooks.or(base_hooks),
+            },
+            on_failure: self.on_failure.or(base.on_failure),
+            retry: match (self.retry, base.retry) {
+                (Some(retry), Some(base_retry)) => Some(retry.or(base_retry)),
+                (retry, base_retry) => retry.or(base_retry),
+            },
+        }
+    }
+}
+
+struct Source<T> {
+    value: T,
+    file: PathBuf,
+}
+
+// Profiles that fail to deserialize keep their error, so that other profiles still work
+type Parsed = std::result::Result<RawProfile, String>;
+
+#[derive(Default)]
+pub struct Config {
+    defaults: Option<Source<Parsed>>,
+    profiles: BTreeMap<String, Source<Parsed>>,
+    files: Vec<PathBuf>, // Loaded config files, in order
+}
+
+impl Config {
+    /// Load a config file, and files it includes.
+    /// A file already loaded is skipped.
+    fn load_file(&mut self, path: &Path) -> Result<()> {
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        if self.files.contains(&canonical) {
+            return Ok(());
+        }
+        self.files.push(canonical);
+
+        let mut full_config = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut full_config))
+            .with_context(|| format!("Cannot read config file {}", path.display()))?;
+        self.load_str(&full_config, path)
+    }
+
+    /// Load config from the content of file at `path`, and files it includes
+    fn load_str(&mut self, full_config: &str, path: &Path) -> Result<()> {
+        // Parse into spanned values first, so that errors in profiles
+        // (like unknown keys) can still be reported with line and column
+        let located = |mut e: toml::de::Error| {
+            e.set_input(Some(full_config));
+            e
+        };
+        let parse = |deserializer, what: &str| {
+            RawProfile::deserialize(deserializer).map_err(|e| {
+                format!(
+                    "Invalid {what} in {}: {}",
+                    path.display(),
+                    located(e).to_string().trim_end()
+                )
+            })
+        };
+        let document = DeTable::parse(full_config)
+            .map_err(located)
+            .with_context(|| format!("Cannot parse config file {}", path.display()))?;
+
+        let mut includes = Vec::new();
+        for (key, value) in document.into_inner() {
+            let deserializer = ValueDeserializer::from(value);
+            match key.get_ref().as_ref() {
+                "include" => {
+                    includes = Vec::<String>::deserialize(deserializer)
+                        .map_err(located)
+                        .with_context(|| format!("Invalid include in {}", path.display()))?;
+                }
+                "defaults" => {
+                    if let Some(existing) = &self.defaults {
+                        anyhow::bail!(
+                            "[defaults] is defined in both {} and {}",
+                            existing.file.display(),
+                            path.display()
+                        );
+                    }
+                    self.defaults = Some(Source {
+                        value: parse(deserializer, "[defaults]"),
+                        file: path.to_path_buf(),
+                    });
+                }
+                name => {
+                    if let Some(existing) = self.profiles.get(name) {
+                        anyhow::bail!(
+                            "Profile '{name}' is defined in both {} and {}",
+                            existing.file.display(),
+                            path.display()
+                        );
+                    }
+                    self.profiles.insert(
+                        name.to_string(),
+                        Source {
+                            value: parse(deserializer, &format!("profile '{name}'")),
+                            file: path.to_path_buf(),
+                        },
+                    );
+                }
+            }
+        }
+
+        // Relative paths are relative to the including file
+        let base = path.parent().unwrap_or_else(|| Path::new("."));
+        for include in includes {
+            self.load_file(&base.join(include))?;
+        }
+        Ok(())
+    }
+
+    /// The file where the profile is defined
+    pub fn profile_file(&self, name: &str) -> Option<&Path> {
+        self.profiles
+            .get(name)
+            .map(|profile| profile.file.as_path())
+    }
+
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    pub fn profile_names(&self) -> impl Iterator<Item = &String> {
+        self.profiles.keys()
+    }
+
+    /// Get the profile with `extends` chain and `[defaults]` merged in
+    pub fn get_profile(&self, name: &str) -> Result<Profile> {
+        let mut chain: Vec<&str> = Vec::new();
+        let mut merged = RawProfile::default();
+        let mut current = Some(name);
+        while let Some(current_name) = current {
+            if chain.contains(&current_name) {
+                chain.push(current_name);
+                anyhow::bail!("Cycle in profile inheritance: {}", chain.join(" -> "));
+            }
+            let Some(Source { value: profile, .. }) = self.profiles.get(current_name) else {
+                if let Some(child) = chain.last() {
+                    anyhow::bail!("Profile '{child}' extends '{current_name}', which is not found");
+                }
+                anyhow::bail!(
+                    "Profile '{name}' not found in config file. Available profiles: {}",
+                    self.profile_names()
+                        .map(String::as_str)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            };
+            let profile = match profile {
+                Ok(profile) => profile,
+                Err(e) => match chain.last() {
+                    Some(child) => {
+                        anyhow::bail!(
+                            "Profile '{child}' extends '{current_name}', which is invalid: {e}"
+                        )
+                    }
+                    None => anyhow::bail!("{e}"),
+                },
+            };
+            chain.push(current_name);
+            current = profile.extends.as_deref();
+            merged = merged.or(profile.clone());
+        }
+        if let Some(defaults) = &self.defaults {
+            let defaults = defaults
+                .value
+                .as_ref()
+                .map_err(|e| anyhow::anyhow!("{e}"))?;
+            merged = merged.or(defaults.clone());
+        }
+
+        Ok(Profile {
+            format: merged
+                .format
+                .with_context(|| format!("Profile '{name}' has no format"))?,
+            image: merged.image.unwrap_or_else(default_image),
+            docker: merged.docker.unwrap_or_else(default_docker),
+            skip_missing_networks: merged.skip_missing_networks,
+            volume_options: merged.volume_options,
+            userns: merged.userns,
+            copy_results: merged.copy_results,
+            uses: merged
+                .uses
+                .with_context(|| format!("Profile '{name}' has no uses"))?,
+            upstream: merged.upstream.unwrap_or_default(),
+            program: merged.program,
+            pass: merged.pass,
+            timeout: merged.timeout,
+            extra: merged.extra,
+            tmp_dir: merged.tmp_dir,
+            log: merged.log,
+            interval: merged.interval,
+            jitter: merged.jitter,
+            write_best: merged.write_best,
+            write_best_format: merged.write_best_format,
+            write_best_margin: merged.write_best_margin,
+            hooks: merged.hooks,
+            on_failure: merged.on_failure,
+            retry: merged.retry,
+        })
+    }
+}
+
+fn get_config_paths() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    let xdg_dir = BaseDirectories::new();
+    if let Some(xdg_config) = xdg_dir.get_config_file("bestbind.conf").as_ref() {
+        paths.push(xdg_config.clone());
+    }
+    if let Some(home) = dirs::home_dir() {
+        let home_path = home.join(".bestbind.conf");
+        paths.push(home_path);
+    }
+    paths.push(Path::new("/etc/bestbind.conf").to_path_buf());
+
+    paths
+}
+
+/// Directories with config snippets, which are all loaded
+fn get_config_dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![Path::new("/etc/bestbind.d").to_path_buf()];
+    let xdg_dir = BaseDirectories::new();
+    if let Some(xdg_config) = xdg_dir.get_config_file("bestbind.d") {
+        dirs.push(xdg_config);
+    }
+    dirs
+}
+
+/// Load config file given by user, or the first one found in default paths
+/// with snippets in bestbind.d directories
+pub fn load(config: Option<&String>) -> Result<Config> {
+    let mut result = Config::default();
+    if let Some(config) = config {
+        result.load_file(Path::new(config))?;
+        return Ok(result);
+    }
+
+    let mut error_msgs = Vec::new();
+    for path in get_config_paths() {
+        match File::open(&path) {
+            Ok(_) => {
+                result.load_file(&path)?;
+                break;
+            }
+            Err(e) => {
+                error_msgs.push(format!("Tried: {}, got error: {e}", path.display()));
+            }
+        }
+    }
+    for dir in get_config_dirs() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            error_msgs.push(format!("Tried: {}/*.conf", dir.display()));
+            continue;
+        };
+        let mut snippets: Vec<_> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "conf") && path.is_file())
+            .collect();
+        snippets.sort();
+        for snippet in snippets {
+            result.load_file(&snippet)?;
+        }
+    }
+    if result.files.is_empty() {
+        anyhow::bail!("Cannot open config file. {}", error_msgs.join("\n"));
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(content: &str) -> Config {
+        let mut config = Config::default();
+        config.load_str(content, Path::new("test.conf")).unwrap();
+        config
+    }
+
+    fn comments(profile: &Profile) -> BTreeMap<&str, &str> {
+        profile
+            .uses
+            .iter()
+            .map(|(network, entry)| {
+                let comment = match entry {
+                    Use::Comment(comment) | Use::Detailed(UseDetail { comment, .. }) => comment,
+                };
+                (network.as_str(), comment.as_str())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn extends_takes_precedence_over_defaults() {
+        let config = config(
+            r#"
+            [defaults]
+            pass = 1
+            timeout = 10
+            program = "curl"
+
+            [parent]
+            format = "ip"
+            pass = 2
+            timeout = 20
+            uses = { "127.0.0.1" = "lo" }
+
+            [child]
+            extends = "parent"
+            pass = 3
+            "#,
+        );
+        let child = config.get_profile("child").unwrap();
+        assert_eq!(child.pass, Some(3));
+        assert_eq!(child.timeout, Some(20));
+        assert_eq!(child.program, Some(Program::Curl));
+        assert_eq!(child.format, Format::IP);
+        let parent = config.get_profile("parent").unwrap();
+        assert_eq!(parent.pass, Some(2));
+    }
+
+    #[test]
+    fn uses_are_merged() {
+        let config = config(
+            r#"
+            [parent]
+            format = "ip"
+            uses = { a = "A", b = "B" }
+
+            [child]
+            extends = "parent"
+            uses = { b = "B2", c = "C" }
+            "#,
+        );
+        let child = config.get_profile("child").unwrap();
+        assert_eq!(
+            comments(&child),
+            BTreeMap::from([("a", "A"), ("b", "B2"), ("c", "C")])
+        );
+    }
+
+    #[test]
+    fn hooks_are_merged_by_field() {
+        let config = config(
+            r#"
+            [parent]
+            format = "ip"
+            uses = { a = "A" }
+            hooks = { on_complete = "parent complete", on_change = "parent change" }
+
+            [child]
+            extends = "parent"
+            hooks = { on_change = "child change" }
+            "#,
+        );
+        let hooks = config.get_profile("child").unwrap().hooks.unwrap();
+        assert_eq!(hooks.on_complete.as_deref(), Some("parent complete"));
+        assert_eq!(hooks.on_change.as_deref(), Some("child change"));
+    }
+
+    #[test]
+    fn cycle_is_detected() {
+        let config = config(
+            r#"
+            [a]
+            extends = "b"
+            [b]
+            extends = "a"
+            "#,
+        );
+        let error = config.get_profile("a").unwrap_err().to_string();
+        assert_eq!(error, "Cycle in profile inheritance: a -> b -> a");
+    }
+
+    #[test]
+    fn missing_parent_is_reported() {
+        let config = config(
+            r#"
+            [a]
+            extends = "nope"
+            "#,
+        );
+        let error = config.get_profile("a").unwrap_err().to_string();
+        assert_eq!(error, "Profile 'a' extends 'nope', which is not found");
+    }
+
+    #[test]
+    fn invalid_profile_does_not_affect_others() {
+        let config = config(
+            r#"
+            [good]
+            format = "ip"
+            uses = { a = "A" }
+
+            [bad]
+            format = "ip"
+            typo = 1
+            "#,
+        );
+        assert!(config.get_profile("good").is_ok());
+        let error = config.get_profile("bad").unwrap_err().to_string();
+        assert!(error.contains("unknown field `typo`"), "{error}");
+        assert!(error.contains("line 8"), "{error}");
+    }
+
+    #[test]
+    fn unknown_key_in_target_is_reported() {
+        let config = config(
+            r#"
+            [good]
+            format = "ip"
+            uses = { a = { comment = "A", tag = "x" } }
+
+            [bad]
+            format = "ip"
+            uses = { a = { comment = "A", tga = "x" } }
+            "#,
+        );
+        let good = config.get_profile("good").unwrap();
+        assert!(matches!(
+            &good.uses["a"],
+            Use::Detailed(UseDetail { tag: Some(tag), .. }) if tag == "x"
+        ));
+        let error = config.get_profile("bad").unwrap_err().to_string();
+        assert!(error.contains("unknown field `tga`"), "{error}");
+        assert!(error.contains("line 8"), "{error}");
+    }
+}
@@ -0,0 +1,190 @@
+/// Test profiles periodically, and keep the latest results
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use signal_hook::consts::SIGHUP;
+use xdg::BaseDirectories;
+
+use crate::{
+    config::{self, Config},
+    history::{self, Record},
+    register_term, run_profile, server, Args, Settings,
+};
+
+struct Job {
+    profile: String,
+    interval: u64,
+    jitter: u64,
+    next: Instant,
+}
+
+/// Latest results of every profile and upstream
+#[derive(Default, Serialize, Deserialize)]
+pub struct Latest {
+    pub records: Vec<Record>,
+}
+
+impl Latest {
+    fn path() -> Result<PathBuf> {
+        BaseDirectories::with_prefix("bestbind")
+            .place_data_file("latest.json")
+            .context("Cannot create data directory")
+    }
+
+    fn load() -> Result<Self> {
+        let path = Self::path()?;
+        match fs::read_to_string(&path) {
+            Ok(s) => {
+                serde_json::from_str(&s).with_context(|| format!("Cannot parse {}", path.display()))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("Cannot read {}", path.display())),
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = Self::path()?;
+        // Write to a temp file and rename, so that readers never see a partial file
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Cannot write {}", tmp.display()))?;
+        fs::rename(&tmp, &path).with_context(|| format!("Cannot write {}", path.display()))
+    }
+
+    fn update(&mut self, records: Vec<Record>) {
+        for record in records {
+            self.records.retain(|r| !r.same_job(&record));
+            self.records.push(record);
+        }
+    }
+}
+
+fn jitter_delay(jitter: u64) -> Duration {
+    Duration::from_secs(rand::rng().random_range(0..=jitter))
+}
+
+/// Jobs of all profiles with `interval` set.
+/// Jobs already scheduled in `old` keep their next run time.
+fn get_jobs(config: &Config, old: &[Job]) -> Vec<Job> {
+    let mut jobs = Vec::new();
+    for name in config.profile_names() {
+        let profile = match config.get_profile(name) {
+            Ok(profile) => profile,
+            Err(e) => {
+                eprintln!("Skipping profile '{name}': {e:#}");
+                continue;
+            }
+        };
+        let Some(interval) = profile.interval else {
+            continue;
+        };
+        let jitter = profile.jitter.unwrap_or(0);
+        let next = old
+            .iter()
+            .find(|job| job.profile == *name)
+            .map_or_else(|| Instant::now() + jitter_delay(jitter), |job| job.next);
+        jobs.push(Job {
+            profile: name.clone(),
+            interval,
+            jitter,
+            next,
+        });
+    }
+    jobs
+}
+
+/// Returns false if terminated by user
+fn run_job(
+    args: &Args,
+    config: &Config,
+    job: &Job,
+    latest: &Mutex<Latest>,
+    term: &Arc<AtomicBool>,
+) -> bool {
+    println!(
+        "[{}] Testing profile {}",
+        history::format_time(history::now()),
+        job.profile
+    );
+    let result = config.get_profile(&job.profile).and_then(|profile| {
+        let settings = Settings::new(args, &job.profile, &profile)?;
+        run_profile(&job.profile, &profile, &settings, term)
+    });
+    match result {
+        Ok(Some(result)) => {
+            let mut latest = latest.lock().unwrap();
+            latest.update(result.records);
+            if let Err(e) = latest.save() {
+                eprintln!("Failed to save latest results: {e:#}");
+            }
+            drop(latest);
+            true
+        }
+        Ok(None) => false,
+        Err(e) => {
+            eprintln!("Failed to test profile {}: {e:#}", job.profile);
+            true
+        }
+    }
+}
+
+pub fn run(args: &Args, listen: Option<&String>) -> Result<()> {
+    let term = register_term();
+    let reload = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGHUP, Arc::clone(&reload))
+        .expect("Register SIGHUP handler failed");
+
+    let mut config = config::load(args.config.as_ref())?;
+    let mut jobs = get_jobs(&config, &[]);
+    if jobs.is_empty() {
+        anyhow::bail!("No profile with interval in config file");
+    }
+    let latest = Arc::new(Mutex::new(Latest::load().unwrap_or_else(|e| {
+        eprintln!("Ignoring latest results saved: {e:#}");
+        Latest::default()
+    })));
+    if let Some(listen) = listen {
+        server::spawn(listen, Arc::clone(&latest))?;
+    }
+
+    while !term.load(Ordering::SeqCst) {
+        if reload.swap(false, Ordering::SeqCst) {
+            // Keep running with the old config if the new one is broken
+            match config::load(args.config.as_ref()) {
+                Ok(new_config) => {
+                    config = new_config;
+                    jobs = get_jobs(&config, &jobs);
+                    println!("Config reloaded, {} job(s)", jobs.len());
+                }
+                Err(e) => eprintln!("Failed to reload config: {e:#}"),
+            }
+        }
+
+        let now = Instant::now();
+        let Some(job) = jobs.iter_mut().min_by_key(|job| job.next) else {
+            // All jobs are removed by reloading, wait for another reload
+            std::thread::sleep(Duration::from_secs(1));
+            continue;
+        };
+        if job.next > now {
+            std::thread::sleep(std::cmp::min(job.next - now, Duration::from_secs(1)));
+            continue;
+        }
+        if !run_job(args, &config, job, &latest, &term) {
+            break;
+        }
+        job.next = Instant::now() + Duration::from_secs(job.interval) + jitter_delay(job.jitter);
+    }
+    println!("Daemon stopped.");
+    Ok(())
+}
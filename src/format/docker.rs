@@ -1,8 +1,9 @@
 use std::{
     fs::File,
     os::unix::process::ExitStatusExt,
-    process::ExitStatus,
-    sync::{atomic::AtomicBool, Arc},
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+    sync::{atomic::AtomicBool, Arc, OnceLock},
     time::Duration,
 };
 
@@ -10,14 +11,262 @@ use rand::{distr::Alphanumeric, Rng};
 
 /// Run with docker, by specifying docker network
 use crate::{
-    format::{get_program_args, wait_timeout, FormatRunner, FormatRunnerFactory, Handle},
+    config::Profile,
+    format::{get_program_args, get_size, wait_timeout, FormatRunner, FormatRunnerFactory, Handle},
     Program, ProgramChild, Target,
 };
 
+/// Docker network details, to tell which uplink it represents
+struct NetworkInfo {
+    driver: String,
+    subnets: Vec<(String, Option<String>)>, // Subnet and gateway
+}
+
+impl std::fmt::Display for NetworkInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "driver {}", self.driver)?;
+        for (subnet, gateway) in &self.subnets {
+            write!(f, ", subnet {subnet}")?;
+            if let Some(gateway) = gateway {
+                write!(f, " via {gateway}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Inspect a network, None if it does not exist
+fn inspect_network(docker: &str, network: &str) -> anyhow::Result<Option<NetworkInfo>> {
+    let output = std::process::Command::new(docker)
+        .args(["network", "inspect", network])
+        .stderr(std::process::Stdio::null())
+        .output()
+        .map_err(|e| anyhow::anyhow!("Failed to run {docker}: {e}"))?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    let value: serde_json::Value = serde_json::from_slice(&output.stdout)
+        .map_err(|e| anyhow::anyhow!("Cannot parse {docker} network inspect output: {e}"))?;
+    let value = &value[0];
+    let text = |v: &serde_json::Value| v.as_str().filter(|s| !s.is_empty()).map(str::to_string);
+    // Docker has IPAM.Config[].Subnet, and podman has subnets[].subnet
+    let subnets = match value["IPAM"]["Config"].as_array() {
+        Some(configs) => configs
+            .iter()
+            .filter_map(|c| Some((text(&c["Subnet"])?, text(&c["Gateway"]))))
+            .collect(),
+        None => value["subnets"]
+            .as_array()
+            .map(|subnets| {
+                subnets
+                    .iter()
+                    .filter_map(|s| Some((text(&s["subnet"])?, text(&s["gateway"]))))
+                    .collect()
+            })
+            .unwrap_or_default(),
+    };
+    Ok(Some(NetworkInfo {
+        driver: text(&value["Driver"])
+            .or_else(|| text(&value["driver"]))
+            .unwrap_or_else(|| "unknown".to_string()),
+        subnets,
+    }))
+}
+
+pub fn validate(profile: &Profile) -> anyhow::Result<()> {
+    let mut missing = Vec::new();
+    for network in profile.uses.keys() {
+        if inspect_network(&profile.docker, network)?.is_none() {
+            missing.push(network.as_str());
+        }
+    }
+    if !missing.is_empty() {
+        missing.sort_unstable();
+        anyhow::bail!("Docker network not found: {}", missing.join(", "));
+    }
+    Ok(())
+}
+
+// Labels of containers started by bestbind, to find leftovers of killed runs.
+// A pid is only meaningful on the same host and in the same PID namespace,
+// and with the start time of the process, it is not mistaken for a reused one.
+const LABEL_RUN_ID: &str = "bestbind.run-id";
+const LABEL_HOST: &str = "bestbind.host";
+const LABEL_PID_NS: &str = "bestbind.pid-ns";
+const LABEL_PID: &str = "bestbind.pid";
+const LABEL_START: &str = "bestbind.start";
+
+fn random_id() -> String {
+    rand::rng()
+        .sample_iter(&Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect()
+}
+
+/// Id shared by all containers started by this bestbind process
+fn run_id() -> &'static str {
+    static RUN_ID: OnceLock<String> = OnceLock::new();
+    RUN_ID.get_or_init(random_id)
+}
+
+/// Identity of a bestbind process
+struct Process {
+    host: String,
+    pid_ns: String,
+    pid: u32,
+    start: String,
+}
+
+/// Start time of a process (in clock ticks since boot), None if it does not exist
+fn process_start(pid: u32) -> Option<String> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // Fields after the command, which may contain spaces, start from state (the 3rd).
+    // Start time is the 22nd.
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19).map(str::to_string)
+}
+
+/// Identity of this process
+fn current_process() -> &'static Process {
+    static PROCESS: OnceLock<Process> = OnceLock::new();
+    PROCESS.get_or_init(|| {
+        let pid = std::process::id();
+        Process {
+            host: std::fs::read_to_string("/proc/sys/kernel/hostname")
+                .map(|host| host.trim().to_string())
+                .unwrap_or_default(),
+            pid_ns: std::fs::read_link("/proc/self/ns/pid")
+                .map(|ns| ns.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            pid,
+            start: process_start(pid).unwrap_or_default(),
+        }
+    })
+}
+
+/// A container started by bestbind
+struct Container {
+    id: String,
+    run_id: String,
+    process: Option<Process>, // None if labels are missing or invalid
+}
+
+impl Container {
+    /// Whether the bestbind process which started it is known to be gone.
+    /// Containers started on other hosts or in other PID namespaces are never orphaned,
+    /// as their processes cannot be checked here.
+    fn orphaned(&self) -> bool {
+        let current = current_process();
+        self.process.as_ref().is_some_and(|process| {
+            !process.host.is_empty()
+                && !process.pid_ns.is_empty()
+                && process.host == current.host
+                && process.pid_ns == current.pid_ns
+                && process_start(process.pid).as_ref() != Some(&process.start)
+        })
+    }
+}
+
+/// Containers (running or not) with bestbind labels, optionally of the given run only
+fn list_containers(docker: &str, run: Option<&str>) -> anyhow::Result<Vec<Container>> {
+    let filter = run.map_or_else(
+        || format!("label={LABEL_RUN_ID}"),
+        |run| format!("label={LABEL_RUN_ID}={run}"),
+    );
+    let output = std::process::Command::new(docker)
+        .args(["ps", "-a", "-q", "--no-trunc", "--filter", &filter])
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| anyhow::anyhow!("Failed to run {docker}: {e}"))?;
+    if !output.status.success() {
+        anyhow::bail!("{docker} ps exited with {}", output.status);
+    }
+    let ids: Vec<_> = String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .map(str::to_string)
+        .collect();
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    // Labels are read by inspect, as docker and podman differ in ps --format
+    let format = [
+        LABEL_RUN_ID,
+        LABEL_HOST,
+        LABEL_PID_NS,
+        LABEL_PID,
+        LABEL_START,
+    ]
+    .iter()
+    .fold("{{.Id}}".to_string(), |format, label| {
+        format + &format!("|{{{{index .Config.Labels \"{label}\"}}}}")
+    });
+    let output = std::process::Command::new(docker)
+        .args(["inspect", "-f", &format])
+        .args(&ids)
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|e| anyhow::anyhow!("Failed to run {docker}: {e}"))?;
+    // Containers removed meanwhile (like by a finished test) are simply missing in output
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<_> = line.trim().split('|').collect();
+            let [id, run_id, host, pid_ns, pid, start] = fields[..] else {
+                return None;
+            };
+            Some(Container {
+                id: id.to_string(),
+                run_id: run_id.to_string(),
+                process: pid.parse().ok().map(|pid| Process {
+                    host: host.to_string(),
+                    pid_ns: pid_ns.to_string(),
+                    pid,
+                    start: start.to_string(),
+                }),
+            })
+        })
+        .collect())
+}
+
+fn remove_containers(docker: &str, containers: &[Container]) -> anyhow::Result<()> {
+    if containers.is_empty() {
+        return Ok(());
+    }
+    let status = std::process::Command::new(docker)
+        .args(["rm", "-f"])
+        .args(containers.iter().map(|c| c.id.as_str()))
+        .stdout(Stdio::null())
+        .status()
+        .map_err(|e| anyhow::anyhow!("Failed to run {docker}: {e}"))?;
+    if !status.success() {
+        anyhow::bail!("{docker} rm exited with {status}");
+    }
+    Ok(())
+}
+
+/// Remove containers left by bestbind processes that are gone on this host (or by any
+/// bestbind process if `all`), returning the number of removed containers
+pub fn cleanup(docker: &str, all: bool) -> anyhow::Result<usize> {
+    let containers: Vec<_> = list_containers(docker, None)?
+        .into_iter()
+        .filter(|c| all || (c.run_id != run_id() && c.orphaned()))
+        .collect();
+    remove_containers(docker, &containers)?;
+    Ok(containers.len())
+}
+
+/// Where the work directory is in containers
+const CONTAINER_DIR: &str = "/bestbind";
+
 pub struct DockerFormatHandle {
     child: ProgramChild,
     ctr_name: String,
     docker: String,
+    output: PathBuf,    // Result in work directory, moved to tmp path after the test
+    ctr_output: String, // Result in container
+    copy_results: bool,
+    size: u64, // Last size measured in container
 }
 pub struct DockerFormatRunner {
     docker: String,
@@ -26,18 +275,166 @@ pub struct DockerFormatRunner {
     extra: Vec<String>,
     program: Program,
     upstream: String,
+    // Mounted to all containers, unless results are copied out
+    work: mktemp::Temp,
+    volume_options: Option<String>,
+    userns: Option<String>,
+    copy_results: bool,
+}
+
+/// Seconds for the program to exit after SIGTERM before SIGKILL, as in ip format
+const STOP_TIMEOUT: u64 = 5;
+
+impl DockerFormatHandle {
+    /// Exit status of the program in container, None if it cannot be inspected
+    fn container_status(&self) -> Option<ExitStatus> {
+        let output = std::process::Command::new(&self.docker)
+            .args(["inspect", "-f", "{{.State.ExitCode}}", &self.ctr_name])
+            .stderr(Stdio::null())
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        let code: i32 = String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse()
+            .ok()?;
+        // The init process exits with 128 + signal when the program is killed by a signal
+        Some(if let signal @ 1..=64 = code - 128 {
+            ExitStatus::from_raw(signal)
+        } else {
+            ExitStatus::from_raw(code << 8)
+        })
+    }
+
+    /// Move result of the exited container to `tmp`
+    fn collect(&self, tmp: &Path) -> anyhow::Result<()> {
+        if self.copy_results {
+            let work = self.output.parent().unwrap();
+            let status = std::process::Command::new(&self.docker)
+                .arg("cp")
+                .arg(format!("{}:{}", self.ctr_name, self.ctr_output))
+                .arg(work)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .map_err(|e| anyhow::anyhow!("Failed to run {}: {e}", self.docker))?;
+            // Nothing to copy if the program fails before writing anything
+            if !status.success() {
+                return Ok(());
+            }
+        }
+        if self.output.symlink_metadata().is_ok() {
+            std::fs::rename(&self.output, tmp)?;
+        }
+        Ok(())
+    }
+
+    /// Status of the exited container (or `client` if not available),
+    /// after moving its result to `tmp` and removing it
+    fn finish(&self, client: ExitStatus, tmp: &Path) -> ExitStatus {
+        let status = self.container_status().unwrap_or(client);
+        if let Err(e) = self.collect(tmp) {
+            eprintln!(
+                "Failed to collect result of container {}: {e:#}",
+                self.ctr_name
+            );
+        }
+        let removed = std::process::Command::new(&self.docker)
+            .args(["rm", "-f", &self.ctr_name])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+        if !removed.is_ok_and(|status| status.success()) {
+            eprintln!("Failed to remove docker container {}", self.ctr_name);
+        }
+        status
+    }
 }
 
 impl Handle for DockerFormatHandle {
-    fn wait_timeout(&mut self, timeout: Duration, term: Arc<AtomicBool>) -> crate::ProgramStatus {
-        wait_timeout(self, timeout, &term)
+    fn wait_timeout(
+        &mut self,
+        timeout: Duration,
+        term: Arc<AtomicBool>,
+        tmp: &Path,
+    ) -> crate::ProgramStatus {
+        let mut status = wait_timeout(self, timeout, &term, tmp);
+        // docker run may exit with its own error code, or be killed before the container
+        status.status = self.finish(status.status, tmp);
+        status
     }
 
     fn child(&mut self) -> &mut ProgramChild {
         &mut self.child
     }
 
+    fn progress(&mut self, _tmp: &Path) -> u64 {
+        if !self.copy_results {
+            return get_size(&self.output);
+        }
+        // Not visible from host, so measured in container, in apparent bytes of
+        // non-directory entries like get_size() (du counts blocks instead).
+        // Keep the last size when it cannot be measured, like before the program writes.
+        let size = std::process::Command::new(&self.docker)
+            .args(["exec", &self.ctr_name, "find", &self.ctr_output])
+            .args(["!", "-type", "d", "-exec", "stat", "-c", "%s", "{}", "+"])
+            .stderr(Stdio::null())
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| {
+                String::from_utf8_lossy(&output.stdout)
+                    .split_whitespace()
+                    .map(|size| size.parse::<u64>().ok())
+                    .sum::<Option<u64>>()
+            });
+        if let Some(size) = size {
+            self.size = size;
+        }
+        self.size
+    }
+
     fn kill_children(&mut self) -> ExitStatus {
+        // Like in ip format, git is killed at once to keep it from cleaning up data,
+        // and others get SIGTERM, and SIGKILL if they are not exiting in time
+        let args = if self.child.program == Program::Git {
+            vec!["kill".to_string(), self.ctr_name.clone()]
+        } else {
+            vec![
+                "stop".to_string(),
+                "--time".to_string(),
+                STOP_TIMEOUT.to_string(),
+                self.ctr_name.clone(),
+            ]
+        };
+        let status = std::process::Command::new(&self.docker)
+            .args(&args)
+            .stdout(Stdio::null())
+            .status()
+            .expect("Failed to stop docker container");
+        if !status.success() {
+            // The container may have exited meanwhile, or never been created
+            eprintln!(
+                "Failed to {} docker container {}, exit code: {}",
+                args[0],
+                self.ctr_name,
+                status.code().unwrap_or(-1)
+            );
+        }
+        // docker run exits after the container, unless it is stuck itself
+        for _ in 0..50 {
+            if let Some(status) = self
+                .child
+                .child
+                .try_wait()
+                .expect("try waiting for child process failed")
+            {
+                return status;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
         self.child
             .child
             .kill()
@@ -45,19 +442,7 @@ impl Handle for DockerFormatHandle {
         self.child
             .child
             .wait()
-            .expect("Failed to wait child process");
-        let status = std::process::Command::new(&self.docker)
-            .args(["kill", self.ctr_name.as_str()])
-            .status()
-            .expect("Failed to kill docker container");
-        assert!(
-            status.success(),
-            "Failed to kill docker container {}, exit code: {}",
-            self.ctr_name,
-            status.code().unwrap_or(-1)
-        );
-
-        ExitStatus::from_raw(128 + libc::SIGKILL)
+            .expect("Failed to wait child process")
     }
 }
 
@@ -69,25 +454,58 @@ impl FormatRunner for DockerFormatRunner {
     }
 
     fn run(&self, target: &str, tmp_path: &mktemp::Temp, log: &File) -> Box<Self::HandleType> {
-        let args = get_program_args(self.program, &self.extra, &self.upstream, tmp_path, None);
-        let ctr_name = format!(
-            "bestbind-{}",
-            rand::rng()
-                .sample_iter(&Alphanumeric)
-                .take(16)
-                .map(char::from)
-                .collect::<String>()
+        let name = tmp_path.file_name().expect("tmp path has no file name");
+        let output = self.work.join(name);
+        let ctr_output = format!("{CONTAINER_DIR}/{}", name.to_string_lossy());
+        let args = get_program_args(
+            self.program,
+            &self.extra,
+            &self.upstream,
+            Path::new(&ctr_output),
+            None,
         );
-        let tmp = tmp_path.as_os_str().to_string_lossy();
-        let cmd = std::process::Command::new(&self.docker)
-            .arg("run")
+        let ctr_name = format!("bestbind-{}", random_id());
+        let process = current_process();
+        let labels = [
+            (LABEL_HOST, process.host.as_str()),
+            (LABEL_PID_NS, &process.pid_ns),
+            (LABEL_PID, &process.pid.to_string()),
+            (LABEL_START, &process.start),
+        ]
+        .map(|(label, value)| ["--label".to_string(), format!("{label}={value}")])
+        .concat();
+        let mut cmd = std::process::Command::new(&self.docker);
+        cmd.arg("run")
             .arg("--name")
             .arg(&ctr_name)
-            .arg("--rm")
+            // The init process forwards signals of docker stop to the program
+            .arg("--init")
+            .arg("--label")
+            .arg(format!("{LABEL_RUN_ID}={}", run_id()))
+            .args(labels)
             .arg("--network")
-            .arg(target)
-            .arg("-v")
-            .arg(format!("{tmp}:{tmp}"))
+            .arg(target);
+        if self.copy_results {
+            // Docker creates the working directory if missing in image
+            cmd.arg("--workdir").arg(CONTAINER_DIR);
+        } else {
+            // Programs see the same kind of tmp path as in ip format
+            if tmp_path.is_dir() {
+                std::fs::create_dir(&output).expect("Failed to create output directory");
+            } else {
+                File::create(&output).expect("Failed to create output file");
+            }
+            let mut volume = format!("{}:{CONTAINER_DIR}", self.work.display());
+            if let Some(options) = &self.volume_options {
+                volume.push(':');
+                volume.push_str(options);
+            }
+            cmd.arg("-v").arg(volume);
+        }
+        if let Some(userns) = &self.userns {
+            cmd.arg(format!("--userns={userns}"));
+        }
+        let child = cmd
             .arg(&self.image)
             .arg(self.program.to_string())
             .args(args)
@@ -98,26 +516,70 @@ impl FormatRunner for DockerFormatRunner {
             .expect("Failed to start docker process");
         Box::new(DockerFormatHandle {
             child: ProgramChild {
-                child: cmd,
+                child,
                 program: self.program,
             },
             ctr_name,
             docker: self.docker.clone(),
+            output,
+            ctr_output,
+            copy_results: self.copy_results,
+            size: 0,
         })
     }
 }
 
+// Containers are removed after each test, but may be left on errors
+impl Drop for DockerFormatRunner {
+    fn drop(&mut self) {
+        let result = list_containers(&self.docker, Some(run_id()))
+            .and_then(|containers| remove_containers(&self.docker, &containers));
+        if let Err(e) = result {
+            eprintln!("Failed to remove containers of this run: {e:#}");
+        }
+    }
+}
+
 impl FormatRunnerFactory for DockerFormatRunner {
     fn create(
-        args: &crate::Args,
-        profile: crate::Profile,
+        settings: &crate::Settings,
+        profile: Profile,
         program: crate::Program,
-    ) -> Box<dyn FormatRunner<HandleType = dyn Handle>> {
+        upstream: &str,
+    ) -> anyhow::Result<Box<dyn FormatRunner<HandleType = dyn Handle>>> {
+        let docker = profile.docker;
+        match cleanup(&docker, false) {
+            Ok(0) => {}
+            Ok(n) => println!("Removed {n} container(s) left by previous bestbind runs"),
+            Err(e) => eprintln!("Failed to clean up containers left by previous runs: {e:#}"),
+        }
+        let mut networks: Vec<_> = profile.uses.into_iter().collect();
+        networks.sort_by(|a, b| a.0.cmp(&b.0));
         let mut uses: Vec<Target> = Vec::new();
-        for (network, comment) in profile.uses {
-            uses.push(Target { network, comment });
+        let mut missing = Vec::new();
+        println!("Docker networks:");
+        for (network, entry) in networks {
+            let target = Target::new(network, entry);
+            if let Some(info) = inspect_network(&docker, &target.network)? {
+                println!("  {} ({}): {info}", target.network, target.comment);
+                uses.push(target);
+            } else {
+                println!("  {} ({}): ❌ not found", target.network, target.comment);
+                missing.push(target.network);
+            }
+        }
+        if !missing.is_empty() {
+            if !profile.skip_missing_networks.unwrap_or(false) {
+                anyhow::bail!(
+                    "Docker network not found: {}. Set `skip_missing_networks = true` in profile to skip them",
+                    missing.join(", ")
+                );
+            }
+            println!("Skipping network(s) not found: {}", missing.join(", "));
+        }
+        if uses.is_empty() {
+            anyhow::bail!("No docker network to test");
         }
-        let docker = profile.docker;
         // Check if an image exists
         let status = std::process::Command::new(&docker)
             .args(["image", "inspect", &profile.image])
@@ -139,13 +601,17 @@ impl FormatRunnerFactory for DockerFormatRunner {
                 status.code().unwrap_or(-1)
             );
         }
-        Box::new(Self {
+        Ok(Box::new(Self {
             docker,
             image: profile.image,
             uses,
-            extra: args.extra.clone(),
+            extra: settings.extra.clone(),
             program,
-            upstream: args.upstream.clone(),
-        })
+            upstream: upstream.to_string(),
+            work: crate::create_tmp_dir(settings.tmp_dir.as_ref()),
+            volume_options: profile.volume_options,
+            userns: profile.userns,
+            copy_results: profile.copy_results.unwrap_or(false),
+        }))
     }
 }
@@ -12,6 +12,7 @@ use std::{
 use libc::{SIGKILL, SIGTERM};
 
 use crate::{
+    config::Profile,
     format::{get_program_args, wait_timeout, FormatRunner, FormatRunnerFactory, Handle},
     get_program_name, Program, ProgramChild, Target,
 };
@@ -38,6 +39,20 @@ You can download corresponding file from https://github.com/taoky/libbinder/rele
     libpath
 }
 
+pub fn validate(profile: &Profile) -> anyhow::Result<()> {
+    let mut invalid: Vec<_> = profile
+        .uses
+        .keys()
+        .filter(|ip| ip.parse::<net::IpAddr>().is_err())
+        .map(String::as_str)
+        .collect();
+    if !invalid.is_empty() {
+        invalid.sort_unstable();
+        anyhow::bail!("Invalid IP address: {}", invalid.join(", "));
+    }
+    Ok(())
+}
+
 fn get_child(
     program: Program,
     bind_ip: &str,
@@ -93,10 +108,13 @@ fn get_child(
     }
 }
 
-fn reap_all_children() {
+fn reap_all_children(pgid: i32) {
+    // Only reap inside the process group of the child:
+    // other targets may be running at the same time in parallel mode,
+    // and reaping them here would steal their exit status.
     loop {
         unsafe {
-            if libc::waitpid(-1, std::ptr::null_mut(), libc::WNOHANG) < 0 {
+            if libc::waitpid(-pgid, std::ptr::null_mut(), libc::WNOHANG) <= 0 {
                 break;
             }
         }
@@ -116,8 +134,13 @@ pub struct IPFormatHandle {
 }
 
 impl Handle for IPFormatHandle {
-    fn wait_timeout(&mut self, timeout: Duration, term: Arc<AtomicBool>) -> crate::ProgramStatus {
-        wait_timeout(self, timeout, &term)
+    fn wait_timeout(
+        &mut self,
+        timeout: Duration,
+        term: Arc<AtomicBool>,
+        tmp: &Path,
+    ) -> crate::ProgramStatus {
+        wait_timeout(self, timeout, &term, tmp)
     }
 
     fn child(&mut self) -> &mut ProgramChild {
@@ -180,7 +203,7 @@ impl Handle for IPFormatHandle {
         }
         // if receiver died before generator, the SIGCHLD handler of generator will help reap it
         // but we cannot rely on race condition to help do things right
-        reap_all_children();
+        reap_all_children(proc.child.id() as i32);
 
         res.unwrap()
     }
@@ -210,18 +233,17 @@ impl FormatRunner for IPFormatRunner {
 
 impl FormatRunnerFactory for IPFormatRunner {
     fn create(
-        args: &crate::Args,
-        profile: crate::Profile,
+        settings: &crate::Settings,
+        profile: Profile,
         program: crate::Program,
-    ) -> Box<dyn FormatRunner<HandleType = dyn Handle>> {
-        let mut uses: Vec<Target> = Vec::new();
-        for (ip, comment) in profile.uses {
-            let _ = ip.parse::<net::IpAddr>().expect("Invalid IP address");
-            uses.push(Target {
-                network: ip,
-                comment,
-            });
-        }
+        upstream: &str,
+    ) -> anyhow::Result<Box<dyn FormatRunner<HandleType = dyn Handle>>> {
+        validate(&profile)?;
+        let uses: Vec<Target> = profile
+            .uses
+            .into_iter()
+            .map(|(ip, entry)| Target::new(ip, entry))
+            .collect();
 
         let binder_path = if program == Program::Git {
             Some(get_binder_path())
@@ -229,12 +251,12 @@ impl FormatRunnerFactory for IPFormatRunner {
             None
         };
 
-        Box::new(Self {
+        Ok(Box::new(Self {
             uses,
             binder_path,
-            extra: args.extra.clone(),
+            extra: settings.extra.clone(),
             program,
-            upstream: args.upstream.clone(),
-        })
+            upstream: upstream.to_string(),
+        }))
     }
 }
@@ -10,20 +10,36 @@ use std::{
     time::{Duration, Instant},
 };
 
+use anyhow::Result;
 use mktemp::Temp;
 
-use crate::{Args, Format, Profile, Program, ProgramChild, ProgramStatus};
+use crate::{
+    config::{Format, Profile},
+    Program, ProgramChild, ProgramStatus, Settings,
+};
 
-mod docker;
+pub mod docker;
 mod ip;
 
+pub const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
 pub trait Handle {
-    fn wait_timeout(&mut self, timeout: Duration, term: Arc<AtomicBool>) -> ProgramStatus;
+    fn wait_timeout(
+        &mut self,
+        timeout: Duration,
+        term: Arc<AtomicBool>,
+        tmp: &Path,
+    ) -> ProgramStatus;
     fn child(&mut self) -> &mut ProgramChild;
     fn kill_children(&mut self) -> ExitStatus;
+    /// Downloaded size so far, sampled while the program is running
+    fn progress(&mut self, tmp: &Path) -> u64 {
+        get_size(tmp)
+    }
 }
 
-pub trait FormatRunner {
+// Runners are shared between worker threads in parallel mode
+pub trait FormatRunner: Sync {
     type HandleType: Handle + ?Sized + 'static;
 
     fn uses(&self) -> &Vec<crate::Target>;
@@ -32,21 +48,31 @@ pub trait FormatRunner {
 
 trait FormatRunnerFactory {
     fn create(
-        args: &Args,
+        settings: &Settings,
         profile: Profile,
         program: Program,
-    ) -> Box<dyn FormatRunner<HandleType = dyn Handle>>;
+        upstream: &str,
+    ) -> Result<Box<dyn FormatRunner<HandleType = dyn Handle>>>;
 }
 
 pub fn get_runner(
     format: Format,
-    args: &Args,
+    settings: &Settings,
     profile: Profile,
     program: Program,
-) -> Box<dyn FormatRunner<HandleType = dyn Handle>> {
+    upstream: &str,
+) -> Result<Box<dyn FormatRunner<HandleType = dyn Handle>>> {
     match format {
-        Format::IP => ip::IPFormatRunner::create(args, profile, program),
-        Format::Docker => docker::DockerFormatRunner::create(args, profile, program),
+        Format::IP => ip::IPFormatRunner::create(settings, profile, program, upstream),
+        Format::Docker => docker::DockerFormatRunner::create(settings, profile, program, upstream),
+    }
+}
+
+/// Check that targets in profile are usable, without running any test
+pub fn validate(profile: &Profile) -> Result<()> {
+    match profile.format {
+        Format::IP => ip::validate(profile),
+        Format::Docker => docker::validate(profile),
     }
 }
 
@@ -104,10 +130,17 @@ fn get_program_args(
     result
 }
 
+/// Size of downloaded file (or directory for git) in bytes
+pub fn get_size(tmp: &Path) -> u64 {
+    // Files may be removed by the program while walking the directory
+    fs_extra::dir::get_size(tmp).unwrap_or(0)
+}
+
 fn wait_timeout(
     handle: &mut impl Handle,
     timeout: Duration,
     term: &Arc<AtomicBool>,
+    tmp: &Path,
 ) -> crate::ProgramStatus {
     // Reference adaptable timeout algorithm from
     // https://github.com/hniksic/rust-subprocess/blob/5e89ac093f378bcfc03c69bdb1b4bcacf4313ce4/src/popen.rs#L778
@@ -117,6 +150,9 @@ fn wait_timeout(
     let deadline = start + timeout;
 
     let mut delay = Duration::from_millis(1);
+    // Downloaded size sampled every second, to tell stalled programs from slow ones
+    let mut progress = Vec::new();
+    let mut next_sample = start + PROGRESS_INTERVAL;
 
     loop {
         let status = handle
@@ -128,23 +164,39 @@ fn wait_timeout(
             return ProgramStatus {
                 status,
                 time: start.elapsed(),
+                timed_out: false,
+                progress,
             };
         }
 
         if term.load(Ordering::SeqCst) {
             let time = start.elapsed();
             let status = handle.kill_children();
-            return ProgramStatus { status, time };
+            return ProgramStatus {
+                status,
+                time,
+                timed_out: false,
+                progress,
+            };
         }
 
         let now = Instant::now();
         if now >= deadline {
             let time = start.elapsed();
             let status = handle.kill_children();
-            return ProgramStatus { status, time };
+            return ProgramStatus {
+                status,
+                time,
+                timed_out: true,
+                progress,
+            };
+        }
+        if now >= next_sample {
+            progress.push(handle.progress(tmp));
+            next_sample += PROGRESS_INTERVAL;
         }
 
-        let remaining = deadline.duration_since(now);
+        let remaining = min(deadline, next_sample).duration_since(now);
         std::thread::sleep(min(delay, remaining));
         delay = min(delay * 2, Duration::from_millis(100));
     }
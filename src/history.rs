@@ -0,0 +1,357 @@
+/// Results of past runs, stored as JSON files in XDG data directory
+use std::{
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use xdg::BaseDirectories;
+
+use crate::Program;
+
+/// How a test ended
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    Completed,            // Program exited with 0
+    TimedOutTransferring, // Killed at timeout, while data was still coming
+    Stalled,              // Killed at timeout, with no data for a while
+    ConnectFailure,       // Program failed to connect to upstream
+    ProgramError,         // Program exited with other non-zero code
+    Killed,               // Program killed by signal, not by us
+    Interrupted,          // Terminated by user
+}
+
+impl Outcome {
+    /// Whether bandwidth of the sample is meaningful
+    pub const fn counts(self) -> bool {
+        matches!(self, Self::Completed | Self::TimedOutTransferring)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Sample {
+    pub bandwidth: f64,    // KB/s
+    pub size: u64,         // Bytes
+    pub duration: f64,     // Seconds
+    pub code: Option<i32>, // Exit code of program, None if killed by signal
+    // None in history saved by older versions
+    #[serde(default)]
+    pub outcome: Option<Outcome>,
+    // Times the test is retried before this sample
+    #[serde(default)]
+    pub retries: usize,
+    // Downloaded bytes sampled every second while running
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub progress: Vec<u64>,
+}
+
+impl Sample {
+    /// Whether the program finished successfully, or was still transferring at timeout
+    pub fn ok(&self, timeout: usize) -> bool {
+        match self.outcome {
+            Some(outcome) => outcome.counts(),
+            None => self.duration > timeout as f64 || self.code == Some(0),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TargetResult {
+    pub network: String,
+    pub comment: String,
+    pub samples: Vec<Sample>, // One for each pass
+    pub score: f64,           // KB/s
+}
+
+impl TargetResult {
+    /// A target fails if no sample is ok
+    pub fn failed(&self, timeout: usize) -> bool {
+        !self.samples.iter().any(|sample| sample.ok(timeout))
+    }
+
+    /// Samples with meaningful bandwidth
+    pub fn succeeded(&self) -> impl Iterator<Item = &Sample> {
+        self.samples
+            .iter()
+            .filter(|sample| sample.outcome.is_none_or(Outcome::counts))
+    }
+
+    /// Number of samples counted in score
+    pub fn successes(&self) -> usize {
+        self.succeeded().count()
+    }
+}
+
+/// Results of testing one upstream with all targets in profile
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Record {
+    pub id: u64,
+    pub time: u64, // Unix timestamp
+    pub profile: String,
+    pub upstream: String,
+    pub program: Program,
+    pub pass: usize,
+    pub timeout: usize,
+    pub targets: Vec<TargetResult>, // Sorted by score, best first
+}
+
+impl Record {
+    pub fn best(&self) -> Option<&TargetResult> {
+        self.targets.first()
+    }
+
+    /// Whether two records are comparable
+    pub fn same_job(&self, other: &Self) -> bool {
+        self.profile == other.profile && self.upstream == other.upstream
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Format unix timestamp in local time
+pub fn format_time(time: u64) -> String {
+    let time = time as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::localtime_r(&raw const time, &raw mut tm);
+    }
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
+fn history_dir() -> Result<PathBuf> {
+    BaseDirectories::with_prefix("bestbind")
+        .create_data_directory("history")
+        .context("Cannot create history directory")
+}
+
+/// Save record with a new id, which is set in record
+pub fn save(record: &mut Record) -> Result<PathBuf> {
+    let dir = history_dir()?;
+    let mut id = load_all()?.last().map_or(1, |last| last.id + 1);
+    loop {
+        let path = dir.join(format!("{id}.json"));
+        // Another bestbind process may be saving at the same time
+        match File::create_new(&path) {
+            Ok(mut file) => {
+                record.id = id;
+                file.write_all(serde_json::to_string_pretty(record)?.as_bytes())
+                    .with_context(|| format!("Cannot write {}", path.display()))?;
+                return Ok(path);
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => id += 1,
+            Err(e) => return Err(e).with_context(|| format!("Cannot create {}", path.display())),
+        }
+    }
+}
+
+/// All records, sorted by id
+pub fn load_all() -> Result<Vec<Record>> {
+    let dir = history_dir()?;
+    let mut records = Vec::new();
+    for entry in fs::read_dir(&dir).with_context(|| format!("Cannot read {}", dir.display()))? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        match fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|s| Ok(serde_json::from_str::<Record>(&s)?))
+        {
+            Ok(record) => records.push(record),
+            Err(e) => eprintln!("Skipping broken history file {}: {e}", path.display()),
+        }
+    }
+    records.sort_by_key(|record| record.id);
+    Ok(records)
+}
+
+/// The last run of the same profile and upstream as record, excluding itself
+pub fn last(record: &Record) -> Result<Option<Record>> {
+    Ok(load_all()?
+        .into_iter()
+        .rev()
+        .find(|r| r.id != record.id && r.same_job(record)))
+}
+
+/// Compare record with the average of the last `n` runs of the same profile and upstream.
+/// Returns warnings for targets slower than the baseline by more than `threshold` percent,
+/// and for the best bind changed since the last run.
+pub fn check_regression(record: &Record, n: usize, threshold: f64) -> Result<Vec<String>> {
+    let records = load_all()?;
+    let previous: Vec<_> = records
+        .iter()
+        .filter(|r| r.id != record.id && r.same_job(record))
+        .rev()
+        .take(n)
+        .collect();
+    let mut warnings = Vec::new();
+    if previous.is_empty() {
+        println!("No previous run of the same profile and upstream as baseline.");
+        return Ok(warnings);
+    }
+
+    for target in &record.targets {
+        let scores: Vec<_> = previous
+            .iter()
+            .filter_map(|r| r.targets.iter().find(|t| t.network == target.network))
+            .map(|t| t.score)
+            .collect();
+        if scores.is_empty() {
+            continue;
+        }
+        let baseline = scores.iter().sum::<f64>() / scores.len() as f64;
+        if baseline <= 0.0 {
+            continue;
+        }
+        let drop = (baseline - target.score) / baseline * 100.0;
+        if drop > threshold {
+            warnings.push(format!(
+                "{} ({}): {:.2} KB/s is {drop:.1}% lower than baseline {baseline:.2} KB/s (last {} run(s))",
+                target.network,
+                target.comment,
+                target.score,
+                scores.len()
+            ));
+        }
+    }
+
+    if let (Some(best), Some(last_best)) = (record.best(), previous[0].best()) {
+        if best.network != last_best.network {
+            warnings.push(format!(
+                "Best bind changed since last run: {} ({}) -> {} ({})",
+                last_best.network, last_best.comment, best.network, best.comment
+            ));
+        }
+    }
+    Ok(warnings)
+}
+
+fn find(records: &[Record], id: u64) -> Result<&Record> {
+    records
+        .iter()
+        .find(|record| record.id == id)
+        .with_context(|| format!("Run {id} not found in history"))
+}
+
+pub fn list() -> Result<()> {
+    for record in load_all()? {
+        let best = record.best().map_or_else(
+            || "-".to_string(),
+            |best| {
+                format!(
+                    "{} ({}): {:.2} KB/s",
+                    best.network, best.comment, best.score
+                )
+            },
+        );
+        println!(
+            "{:>4}  {}  {}  {}  {}  best: {best}",
+            record.id,
+            format_time(record.time),
+            record.profile,
+            record.program,
+            record.upstream
+        );
+    }
+    Ok(())
+}
+
+pub fn show(id: u64) -> Result<()> {
+    let records = load_all()?;
+    let record = find(&records, id)?;
+    println!("Run {} at {}", record.id, format_time(record.time));
+    println!("Profile: {}", record.profile);
+    println!("Upstream: {} ({})", record.upstream, record.program);
+    println!("Passes: {}, timeout: {}s", record.pass, record.timeout);
+    for target in &record.targets {
+        let samples: Vec<_> = target
+            .samples
+            .iter()
+            .map(|sample| format!("{:.2}", sample.bandwidth))
+            .collect();
+        println!(
+            "{} ({}): {:.2} KB/s (samples: {})",
+            target.network,
+            target.comment,
+            target.score,
+            samples.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Compare the latest run with the given one, or the previous one of the same profile and upstream
+pub fn compare(previous: Option<u64>) -> Result<()> {
+    let records = load_all()?;
+    let latest = records.last().context("No run in history")?;
+    let previous = match previous {
+        Some(id) => find(&records, id)?,
+        None => records
+            .iter()
+            .rev()
+            .skip(1)
+            .find(|record| record.same_job(latest))
+            .context("No previous run of the same profile and upstream in history")?,
+    };
+    println!(
+        "Run {} at {} compared with run {} at {}:",
+        latest.id,
+        format_time(latest.time),
+        previous.id,
+        format_time(previous.time)
+    );
+    if !latest.same_job(previous) {
+        println!(
+            "Note: comparing {} ({}) with {} ({})",
+            latest.profile, latest.upstream, previous.profile, previous.upstream
+        );
+    }
+    for target in &latest.targets {
+        match previous
+            .targets
+            .iter()
+            .find(|t| t.network == target.network)
+        {
+            Some(old) => {
+                let delta = if old.score > 0.0 {
+                    format!("{:+.1}%", (target.score - old.score) / old.score * 100.0)
+                } else {
+                    "-".to_string()
+                };
+                println!(
+                    "{} ({}): {:.2} KB/s -> {:.2} KB/s ({delta})",
+                    target.network, target.comment, old.score, target.score
+                );
+            }
+            None => println!(
+                "{} ({}): - -> {:.2} KB/s (new)",
+                target.network, target.comment, target.score
+            ),
+        }
+    }
+    for old in &previous.targets {
+        if !latest.targets.iter().any(|t| t.network == old.network) {
+            println!(
+                "{} ({}): {:.2} KB/s -> - (removed)",
+                old.network, old.comment, old.score
+            );
+        }
+    }
+    Ok(())
+}
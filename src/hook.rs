@@ -0,0 +1,120 @@
+/// Commands run after testing an upstream, with the results
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::history::Record;
+
+/// Hook commands, run by `sh -c`
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Hooks {
+    // Run every time final results are ready
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_complete: Option<String>,
+    // Run only when the best bind differs from the last run in history
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_change: Option<String>,
+}
+
+impl Hooks {
+    /// Fill hooks missing in self with those in base
+    pub fn or(self, base: Self) -> Self {
+        Self {
+            on_complete: self.on_complete.or(base.on_complete),
+            on_change: self.on_change.or(base.on_change),
+        }
+    }
+}
+
+const PREFIX: &str = "BESTBIND_HOOK";
+
+fn envs(event: &str, record: &Record, previous: Option<&Record>) -> Vec<(String, String)> {
+    let mut envs = vec![
+        ("EVENT".to_string(), event.to_string()),
+        ("PROFILE".to_string(), record.profile.clone()),
+        ("UPSTREAM".to_string(), record.upstream.clone()),
+        ("PROGRAM".to_string(), record.program.to_string()),
+        (
+            "RANKING".to_string(),
+            record
+                .targets
+                .iter()
+                .map(|target| target.network.as_str())
+                .collect::<Vec<_>>()
+                .join(" "),
+        ),
+        ("TARGETS".to_string(), record.targets.len().to_string()),
+    ];
+    if let Some(best) = record.best() {
+        envs.push(("BEST".to_string(), best.network.clone()));
+        envs.push(("BEST_COMMENT".to_string(), best.comment.clone()));
+        envs.push(("BEST_BANDWIDTH".to_string(), best.score.to_string()));
+    }
+    if let Some(best) = previous.and_then(Record::best) {
+        envs.push(("PREVIOUS_BEST".to_string(), best.network.clone()));
+        envs.push(("PREVIOUS_BEST_COMMENT".to_string(), best.comment.clone()));
+        envs.push((
+            "PREVIOUS_BEST_BANDWIDTH".to_string(),
+            best.score.to_string(),
+        ));
+    }
+    // Numbered by rank, starting from 1
+    for (i, target) in record.targets.iter().enumerate() {
+        let i = i + 1;
+        envs.push((format!("TARGET_{i}_NETWORK"), target.network.clone()));
+        envs.push((format!("TARGET_{i}_COMMENT"), target.comment.clone()));
+        envs.push((format!("TARGET_{i}_BANDWIDTH"), target.score.to_string()));
+    }
+    envs.into_iter()
+        .map(|(key, value)| (format!("{PREFIX}_{key}"), value))
+        .collect()
+}
+
+fn execute(command: &str, event: &str, record: &Record, previous: Option<&Record>) -> Result<()> {
+    println!("Running {event} hook: {command}");
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(envs(event, record, previous))
+        .stdin(Stdio::piped())
+        .spawn()
+        .context("Cannot run hook")?;
+    let json = serde_json::to_string_pretty(record)?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // The hook may exit without reading stdin
+        let _ = stdin.write_all(json.as_bytes());
+    }
+    let status = child.wait().context("Cannot wait for hook")?;
+    if !status.success() {
+        anyhow::bail!("Hook exited with {status}");
+    }
+    Ok(())
+}
+
+/// Run hooks for the record. `previous` is the last run of the same profile and upstream.
+/// Failed hooks are reported, but do not fail the run.
+pub fn run(hooks: &Hooks, record: &Record, previous: Option<&Record>) {
+    if let Some(command) = &hooks.on_complete {
+        if let Err(e) = execute(command, "complete", record, previous) {
+            eprintln!("Hook failed: {e:#}");
+        }
+    }
+    if let Some(command) = &hooks.on_change {
+        // A run with all targets failed has no meaningful winner
+        let best = record
+            .best()
+            .filter(|best| best.score > 0.0)
+            .map(|best| &best.network);
+        let previous_best = previous.and_then(Record::best).map(|best| &best.network);
+        if best.is_some() && best != previous_best {
+            if let Err(e) = execute(command, "change", record, previous) {
+                eprintln!("Hook failed: {e:#}");
+            }
+        }
+    }
+}
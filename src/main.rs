@@ -9,11 +9,11 @@
 )]
 
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet},
     fs::File,
-    io::Read,
-    path::{Path, PathBuf},
-    process::{self, ExitStatus},
+    num::NonZeroUsize,
+    path::Path,
+    process::{self, ExitCode, ExitStatus},
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
@@ -21,17 +21,35 @@ use std::{
     time::Duration,
 };
 
-use anyhow::Result;
-use clap::{Parser, ValueEnum};
-use serde::Deserialize;
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
 use signal_hook::consts::{SIGINT, SIGTERM};
-use xdg::BaseDirectories;
 
-use crate::format::get_runner;
+use crate::{
+    best::BestFormat,
+    config::{Format, Profile, Use, UseDetail},
+    format::{get_runner, FormatRunner, Handle},
+    history::{Outcome, Record, Sample, TargetResult},
+    hook::Hooks,
+    schedule::Scheduler,
+    unit::Unit,
+};
 
+mod best;
+mod chart;
+mod config;
+mod daemon;
 mod format;
+mod history;
+mod hook;
+mod report;
+mod schedule;
+mod server;
+mod unit;
 
-#[derive(Debug, ValueEnum, Clone, Copy, PartialEq)]
+#[derive(Debug, ValueEnum, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
 enum Program {
     Rsync,
     Wget,
@@ -51,97 +69,388 @@ impl std::fmt::Display for Program {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-enum Format {
-    IP,
-    Docker,
-}
-
-impl<'de> Deserialize<'de> for Format {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        let s: String = Deserialize::deserialize(deserializer)?;
-        let s = s.to_lowercase();
-        match s.as_str() {
-            "ip" => Ok(Self::IP),
-            "docker" => Ok(Self::Docker),
-            _ => Err(serde::de::Error::custom(format!(
-                "Unknown format: {s}. Supported formats: ip, docker"
-            ))),
-        }
-    }
-}
-
-fn default_docker() -> String {
-    "docker".to_string()
-}
-
-fn default_image() -> String {
-    "ghcr.io/taoky/bestbind-env:master".to_string()
-}
-
-#[derive(Debug, Deserialize, Clone)]
-struct Profile {
-    format: Format,
-    #[serde(default = "default_image")]
-    image: String, // Docker image name, only used in Docker format
-    #[serde(default = "default_docker")]
-    docker: String, // The "Docker" command, default to "docker".
-    // A possible alternative is "podman"
-    uses: HashMap<String, String>, // IP or Docker network => comment
-}
-
 #[derive(Parser, Debug)]
 #[clap(about, version)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// Profile name in config file. If not given, it will use "default" profile
-    #[clap(long, default_value = "default")]
+    #[clap(
+        long,
+        default_value = "default",
+        global = true,
+        env = "BESTBIND_PROFILE"
+    )]
     profile: String,
 
     /// Config file (IP list) path. Select order is bestbind.conf in XDG config,
     /// then ~/.bestbind.conf, then /etc/bestbind.conf
-    #[clap(short, long)]
+    #[clap(short, long, global = true, env = "BESTBIND_CONFIG")]
     config: Option<String>,
 
-    /// Passes number
-    #[clap(short, long, default_value = "3")]
-    pass: usize,
+    /// Passes number [default: 3]
+    #[clap(short, long, env = "BESTBIND_PASS")]
+    pass: Option<usize>,
 
-    /// Timeout (seconds)
-    #[clap(short, long, default_value = "30")]
-    timeout: usize,
+    /// Timeout (seconds) [default: 30]
+    #[clap(short, long, env = "BESTBIND_TIMEOUT")]
+    timeout: Option<usize>,
 
     /// Tmp file path. Default to `env::temp_dir()` (/tmp in Linux system)
-    #[clap(long)]
+    #[clap(long, env = "BESTBIND_TMP_DIR")]
     tmp_dir: Option<String>,
 
     /// Log file. Default to /dev/null
     /// When speedtesting, the executed program output is redirected to this file.
-    #[clap(long, default_value = "/dev/null")]
-    log: String,
+    #[clap(long, env = "BESTBIND_LOG")]
+    log: Option<String>,
 
-    /// Upstream path. Will be given to specified program
+    /// Upstream path. Will be given to specified program.
+    /// Multiple upstreams can be given to test every (upstream, target) pair.
+    /// Can be omitted if the profile provides it
     #[clap(value_parser)]
-    upstream: String,
+    upstream: Vec<String>,
 
     /// Program to use. It will try to detect by default (here curl will be used default for http(s))
-    #[clap(long, value_enum)]
+    #[clap(long, value_enum, env = "BESTBIND_PROGRAM")]
     program: Option<Program>,
 
     /// Extra arguments. Will be given to specified program
-    #[clap(long, allow_hyphen_values = true, value_parser = parse_extra)]
-    extra: Vec<String>,
+    #[clap(long, allow_hyphen_values = true, value_parser = parse_extra, env = "BESTBIND_EXTRA")]
+    extra: Option<ExtraArgs>,
+
+    /// Number of targets to test at the same time in each pass
+    #[clap(long, default_value = "1", env = "BESTBIND_PARALLEL")]
+    parallel: NonZeroUsize,
+
+    /// With --parallel, never test targets with the same tag at the same time,
+    /// so that targets on the same uplink don't compete for bandwidth
+    #[clap(long, env = "BESTBIND_DISTINCT_TAGS")]
+    distinct_tags: bool,
+
+    /// Don't save results to history
+    #[clap(long, env = "BESTBIND_NO_HISTORY")]
+    no_history: bool,
+
+    /// Compare results with the average of the last N runs in history.
+    /// Warn and exit with code 3 if a target drops more than --regression-threshold,
+    /// or the best bind changed since last run
+    #[clap(long, env = "BESTBIND_BASELINE")]
+    baseline: Option<usize>,
+
+    /// Bandwidth drop (percent) compared with baseline to be taken as a regression
+    #[clap(long, default_value = "20", env = "BESTBIND_REGRESSION_THRESHOLD")]
+    regression_threshold: f64,
+
+    /// Write the best bind to this file (atomically), if any target has a positive result
+    #[clap(long, env = "BESTBIND_WRITE_BEST")]
+    write_best: Option<String>,
+
+    /// Format of --write-best file [default: plain]
+    #[clap(long, value_enum, env = "BESTBIND_WRITE_BEST_FORMAT")]
+    write_best_format: Option<BestFormat>,
+
+    /// Only replace the bind in --write-best file if the new best one is faster
+    /// than it by more than this percent [default: 0]
+    #[clap(long, env = "BESTBIND_WRITE_BEST_MARGIN")]
+    write_best_margin: Option<f64>,
+
+    /// Shell command to run after testing each upstream, with results in
+    /// `BESTBIND_HOOK_*` environment variables and JSON on stdin
+    #[clap(long, env = "BESTBIND_ON_COMPLETE")]
+    on_complete: Option<String>,
+
+    /// Like --on-complete, but only run when the best bind differs from
+    /// the last run of the same profile and upstream in history
+    #[clap(long, env = "BESTBIND_ON_CHANGE")]
+    on_change: Option<String>,
+
+    /// Exit with non-zero code when any target fails, all targets fail, or never.
+    /// A target fails if none of its passes succeeds
+    #[clap(long, value_enum, default_value = "all", env = "BESTBIND_FAIL_ON")]
+    fail_on: FailOn,
+
+    /// How failed passes (like stalled or failed to connect) of a target affect its result:
+    /// count as 0 KB/s, exclude them, or exclude them and scale the result
+    /// by the rate of succeeded passes [default: exclude]
+    #[clap(long, value_enum, env = "BESTBIND_ON_FAILURE")]
+    on_failure: Option<FailurePolicy>,
+
+    /// Max times to retry a test when the program fails with a transient error,
+    /// like rsync exiting with 5 or 10 [default: 1]
+    #[clap(long, env = "BESTBIND_RETRIES")]
+    retries: Option<usize>,
+
+    /// Unit to show bandwidth in
+    #[clap(long, value_enum, default_value = "auto", env = "BESTBIND_UNIT")]
+    unit: Unit,
+
+    /// Also show final results as a bar chart, with bandwidth of each pass.
+    /// Set `NO_COLOR` to disable colors
+    #[clap(long, env = "BESTBIND_CHART")]
+    chart: bool,
+
+    /// Write a report of results to PATH. Only "html" FORMAT is supported,
+    /// which is a single HTML file with charts. In environment variable,
+    /// give both separated by a space
+    #[clap(long, num_args = 2, value_names = ["FORMAT", "PATH"], env = "BESTBIND_REPORT")]
+    report: Option<Vec<String>>,
 }
 
+#[derive(Debug, ValueEnum, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum FailurePolicy {
+    Zero,
+    Exclude,
+    Penalize,
+}
+
+#[derive(Debug, ValueEnum, Clone, Copy, PartialEq, Eq)]
+enum FailOn {
+    Any,
+    All,
+    None,
+}
+
+/// Exit code when regression is detected with --baseline
+const EXIT_REGRESSION: u8 = 3;
+/// Exit code when some targets failed, with --fail-on any
+const EXIT_SOME_FAILED: u8 = 4;
+/// Exit code when all targets failed, unless --fail-on none
+const EXIT_ALL_FAILED: u8 = 5;
+/// Exit code when terminated by user, like shells do for SIGINT
+const EXIT_INTERRUPTED: u8 = 130;
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Inspect config file
+    Config {
+        #[clap(subcommand)]
+        command: ConfigCommand,
+    },
+    /// List all profiles and their targets
+    Profiles {
+        /// Print in JSON format
+        #[clap(long)]
+        json: bool,
+    },
+    /// Test profiles with `interval` set periodically
+    Daemon {
+        /// Serve latest results over HTTP on this address (like 127.0.0.1:9091),
+        /// or Unix socket (like `unix:/run/bestbind.sock`)
+        #[clap(long, env = "BESTBIND_LISTEN")]
+        listen: Option<String>,
+    },
+    /// Inspect results of past runs
+    History {
+        #[clap(subcommand)]
+        command: HistoryCommand,
+    },
+    /// Manage containers of docker format
+    Docker {
+        #[clap(subcommand)]
+        command: DockerCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum DockerCommand {
+    /// Remove containers left by bestbind runs that were killed
+    Cleanup {
+        /// Also remove containers of bestbind runs still running
+        #[clap(long)]
+        all: bool,
+        /// Docker command to use (default to those of docker profiles in config file,
+        /// or "docker")
+        #[clap(long)]
+        docker: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum HistoryCommand {
+    /// List all past runs
+    List,
+    /// Show samples and results of a run
+    Show { id: u64 },
+    /// Compare the latest run with a previous one (default to the last run
+    /// of the same profile and upstream)
+    Compare { id: Option<u64> },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Print the profile with `extends` and `[defaults]` resolved
+    Show,
+    /// Validate all profiles in all config files
+    Check,
+}
+
+// An alias, so that clap takes it as a single value instead of multiple ones
+type ExtraArgs = Vec<String>;
+
 fn parse_extra(extra: &str) -> Result<Vec<String>, String> {
     shlex::split(extra).map_or_else(|| Err("Failed to parse extra arguments".to_string()), Ok)
 }
 
+/// Options of a run. Command line options override those in profile.
+struct Settings {
+    upstream: Vec<String>,
+    program: Option<Program>,
+    pass: usize,
+    timeout: usize,
+    extra: Vec<String>,
+    tmp_dir: Option<String>,
+    log: String,
+    parallel: NonZeroUsize,
+    distinct_tags: bool,
+    no_history: bool,
+    baseline: Option<usize>,
+    regression_threshold: f64,
+    write_best: Option<String>,
+    write_best_format: BestFormat,
+    write_best_margin: f64,
+    hooks: Hooks,
+    on_failure: FailurePolicy,
+    retries: usize,
+    retry_codes: BTreeMap<Program, Vec<i32>>,
+    unit: Unit,
+    chart: bool,
+    report: Option<String>, // Path of HTML report
+}
+
+fn report_path(format: &str, path: &str) -> Result<String> {
+    if format != "html" {
+        anyhow::bail!("Unsupported report format {format}. Supported formats: html");
+    }
+    Ok(path.to_string())
+}
+
+impl Settings {
+    fn new(args: &Args, name: &str, profile: &Profile) -> Result<Self> {
+        let upstream = if !args.upstream.is_empty() {
+            args.upstream.clone()
+        } else {
+            profile.upstream.clone()
+        };
+        if upstream.is_empty() {
+            anyhow::bail!(
+                "No upstream given. Please specify it in command line or in profile '{name}'"
+            );
+        }
+        let extra = match (&args.extra, &profile.extra) {
+            (Some(extra), _) => extra.clone(),
+            (None, Some(extra)) => parse_extra(extra).map_err(anyhow::Error::msg)?,
+            (None, None) => Vec::new(),
+        };
+        Ok(Self {
+            upstream,
+            program: args.program.or(profile.program),
+            pass: args.pass.or(profile.pass).unwrap_or(3),
+            timeout: args.timeout.or(profile.timeout).unwrap_or(30),
+            extra,
+            tmp_dir: args.tmp_dir.clone().or_else(|| profile.tmp_dir.clone()),
+            log: args
+                .log
+                .clone()
+                .or_else(|| profile.log.clone())
+                .unwrap_or_else(|| "/dev/null".to_string()),
+            parallel: args.parallel,
+            distinct_tags: args.distinct_tags,
+            no_history: args.no_history,
+            baseline: args.baseline,
+            regression_threshold: args.regression_threshold,
+            write_best: args
+                .write_best
+                .clone()
+                .or_else(|| profile.write_best.clone()),
+            write_best_format: args
+                .write_best_format
+                .or(profile.write_best_format)
+                .unwrap_or(BestFormat::Plain),
+            write_best_margin: args
+                .write_best_margin
+                .or(profile.write_best_margin)
+                .unwrap_or(0.0),
+            hooks: Hooks {
+                on_complete: args.on_complete.clone(),
+                on_change: args.on_change.clone(),
+            }
+            .or(profile.hooks.clone().unwrap_or_default()),
+            on_failure: args
+                .on_failure
+                .or(profile.on_failure)
+                .unwrap_or(FailurePolicy::Exclude),
+            retries: args
+                .retries
+                .or_else(|| profile.retry.as_ref().and_then(|retry| retry.times))
+                .unwrap_or(1),
+            retry_codes: profile
+                .retry
+                .as_ref()
+                .map(|retry| retry.codes.clone())
+                .unwrap_or_default(),
+            unit: args.unit,
+            chart: args.chart,
+            report: match args.report.as_deref() {
+                None => None,
+                // BESTBIND_REPORT gives "FORMAT PATH" as one value
+                Some([value]) => match value.split_once(char::is_whitespace) {
+                    Some((format, path)) => Some(report_path(format, path.trim_start())?),
+                    None => anyhow::bail!("BESTBIND_REPORT should be like \"html report.html\""),
+                },
+                Some([format, path]) => Some(report_path(format, path)?),
+                Some(_) => unreachable!("--report takes 2 values"),
+            },
+        })
+    }
+
+    /// Exit codes of program to retry on
+    fn retry_codes(&self, program: Program) -> &[i32] {
+        self.retry_codes
+            .get(&program)
+            .map_or_else(|| default_retry_codes(program), Vec::as_slice)
+    }
+}
+
+/// Exit codes of transient errors, which are worth retrying
+const fn default_retry_codes(program: Program) -> &'static [i32] {
+    match program {
+        // Error starting client-server protocol (like max connections reached),
+        // socket I/O error
+        Program::Rsync => &[5, 10],
+        // Couldn't resolve host, couldn't connect
+        Program::Curl => &[6, 7],
+        // Network failure
+        Program::Wget => &[4],
+        Program::Git => &[],
+    }
+}
+
+/// Wait before retrying, so that transient errors can go away
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Serialize)]
 struct Target {
     network: String,
     comment: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<String>,
+}
+
+impl Target {
+    fn new(network: String, entry: Use) -> Self {
+        let (comment, tag) = match entry {
+            Use::Comment(comment) => (comment, None),
+            Use::Detailed(UseDetail { comment, tag }) => (comment, tag),
+        };
+        Self {
+            network,
+            comment,
+            tag,
+        }
+    }
 }
 
 #[inline]
@@ -174,6 +483,65 @@ fn create_tmp_dir(tmp_dir: Option<&String>) -> mktemp::Temp {
 struct ProgramStatus {
     status: ExitStatus,
     time: Duration,
+    timed_out: bool,
+    progress: Vec<u64>, // Downloaded size sampled every PROGRESS_INTERVAL
+}
+
+/// A program killed at timeout is taken as stalled,
+/// if no data comes in this many progress samples before that
+const STALL_SAMPLES: usize = 5;
+
+/// Exit codes meaning that the program cannot connect to upstream
+const fn is_connect_failure(program: Program, code: i32) -> bool {
+    match program {
+        // Couldn't resolve host, couldn't connect, SSL connect error
+        Program::Curl => matches!(code, 5..=7 | 35),
+        // Network failure
+        Program::Wget => code == 4,
+        // Error starting client-server protocol, socket I/O error,
+        // timeout waiting for daemon connection
+        Program::Rsync => matches!(code, 5 | 10 | 35),
+        // git exits with 128 for nearly all errors
+        Program::Git => false,
+    }
+}
+
+fn classify(program: Program, status: &ProgramStatus, size: u64, interrupted: bool) -> Outcome {
+    if interrupted {
+        return Outcome::Interrupted;
+    }
+    if status.timed_out {
+        let before = status
+            .progress
+            .len()
+            .checked_sub(STALL_SAMPLES + 1)
+            .map_or(0, |i| status.progress[i]);
+        return if size > before {
+            Outcome::TimedOutTransferring
+        } else {
+            Outcome::Stalled
+        };
+    }
+    match status.status.code() {
+        Some(0) => Outcome::Completed,
+        Some(code) if is_connect_failure(program, code) => Outcome::ConnectFailure,
+        Some(_) => Outcome::ProgramError,
+        None => Outcome::Killed,
+    }
+}
+
+fn describe(outcome: Outcome, program: Program, code: Option<i32>) -> String {
+    let name = get_program_name(program);
+    let code = code.map_or_else(|| "-".to_string(), |code| code.to_string());
+    match outcome {
+        Outcome::Completed => "✅ OK".to_owned(),
+        Outcome::TimedOutTransferring => format!("✅ {name} timeout as expected"),
+        Outcome::Stalled => format!("❌ {name} stalled, no data before timeout"),
+        Outcome::ConnectFailure => format!("❌ {name} failed to connect with code {code}"),
+        Outcome::ProgramError => format!("❌ {name} failed with code {code}"),
+        Outcome::Killed => format!("❌ {name} killed by signal"),
+        Outcome::Interrupted => format!("❌ {name} terminated by user"),
+    }
 }
 
 struct ProgramChild {
@@ -181,176 +549,812 @@ struct ProgramChild {
     program: Program,
 }
 
-fn get_config_paths(args: &Args) -> Vec<PathBuf> {
-    if let Some(config) = args.config.as_ref() {
-        vec![Path::new(config).to_path_buf()]
-    } else {
-        let mut paths = Vec::new();
-        let xdg_dir = BaseDirectories::new();
-        if let Some(xdg_config) = xdg_dir.get_config_file("bestbind.conf").as_ref() {
-            paths.push(xdg_config.clone());
-        }
-        if let Some(home) = dirs::home_dir() {
-            let home_path = home.join(".bestbind.conf");
-            paths.push(home_path);
+fn test_target(
+    runner: &dyn FormatRunner<HandleType = dyn Handle>,
+    target: &Target,
+    settings: &Settings,
+    program: Program,
+    log: &File,
+    term: &Arc<AtomicBool>,
+) -> Sample {
+    let mut retries = 0;
+    loop {
+        // create tmp file or directory
+        let tmp_file = if program != Program::Git {
+            create_tmp_file(settings.tmp_dir.as_ref())
+        } else {
+            create_tmp_dir(settings.tmp_dir.as_ref())
+        };
+        let mut proc = runner.run(&target.network, &tmp_file, log);
+        let prog_status = proc.wait_timeout(
+            Duration::from_secs(settings.timeout as u64),
+            term.clone(),
+            &tmp_file,
+        );
+        let status = prog_status.status;
+        let duration = prog_status.time;
+        let duration_seconds = duration.as_secs_f64();
+        // check file size
+        let size = format::get_size(&tmp_file);
+        let outcome = classify(program, &prog_status, size, term.load(Ordering::SeqCst));
+        if matches!(outcome, Outcome::ConnectFailure | Outcome::ProgramError)
+            && status
+                .code()
+                .is_some_and(|code| settings.retry_codes(program).contains(&code))
+            && retries < settings.retries
+            && !term.load(Ordering::SeqCst)
+        {
+            println!(
+                "{} ({}): {}, retrying ({}/{})",
+                target.network,
+                target.comment,
+                describe(outcome, program, status.code()),
+                retries + 1,
+                settings.retries
+            );
+            std::thread::sleep(RETRY_DELAY);
+            // Only count the retry if it runs
+            if !term.load(Ordering::SeqCst) {
+                retries += 1;
+                continue;
+            }
         }
-        paths.push(Path::new("/etc/bestbind.conf").to_path_buf());
-
-        paths
+        let bandwidth = size as f64 / duration_seconds; // Bytes / Seconds
+        let bandwidth = bandwidth / 1024_f64; // KB/s
+        let retry_note = match retries {
+            0 => String::new(),
+            1 => ", retried once".to_string(),
+            n => format!(", retried {n} times"),
+        };
+        println!(
+            "{} ({}): {} ({}{retry_note})",
+            target.network,
+            target.comment,
+            settings.unit.format(bandwidth),
+            describe(outcome, program, status.code())
+        );
+        return Sample {
+            bandwidth,
+            size,
+            duration: duration_seconds,
+            code: status.code(),
+            outcome: Some(outcome),
+            retries,
+            progress: prog_status.progress,
+        };
     }
 }
 
-fn get_profile(args: &Args, config: &str) -> Result<Profile> {
-    let profiles: HashMap<String, Profile> = toml::from_str(config)?;
-    match profiles.get(&args.profile) {
-        Some(profile) => Ok(profile.clone()),
-        None => Err(anyhow::anyhow!(
-            "Profile '{}' not found in config file",
-            args.profile
-        )),
+/// Test every target once, with up to `settings.parallel` targets at the same time.
+/// Returns None if terminated by user.
+fn run_pass(
+    runner: &dyn FormatRunner<HandleType = dyn Handle>,
+    settings: &Settings,
+    program: Program,
+    log: &File,
+    term: &Arc<AtomicBool>,
+) -> Option<Vec<Sample>> {
+    let uses = runner.uses();
+    let scheduler = Scheduler::new(uses, settings.distinct_tags);
+    let mut results_pass = vec![None; uses.len()];
+    let workers = settings.parallel.get().min(uses.len());
+    std::thread::scope(|s| {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                s.spawn(|| {
+                    let mut finished = Vec::new();
+                    while !term.load(Ordering::SeqCst) {
+                        let Some(ticket) = scheduler.next() else {
+                            break;
+                        };
+                        let sample =
+                            test_target(runner, &uses[ticket.index], settings, program, log, term);
+                        finished.push((ticket.index, sample));
+                    }
+                    finished
+                })
+            })
+            .collect();
+        for handle in handles {
+            for (index, sample) in handle.join().expect("worker thread panicked") {
+                results_pass[index] = Some(sample);
+            }
+        }
+    });
+    if term.load(Ordering::SeqCst) {
+        return None;
     }
+    results_pass.into_iter().collect()
 }
 
-fn main() {
-    let args = Args::parse();
-    let config_paths = get_config_paths(&args);
-    let log = File::create(&args.log).expect("Cannot open log file");
-    let term = Arc::new(AtomicBool::new(false));
-    signal_hook::flag::register(SIGINT, Arc::clone(&term)).expect("Register SIGINT handler failed");
-    signal_hook::flag::register(SIGTERM, Arc::clone(&term))
-        .expect("Register SIGTERM handler failed");
+fn detect_program(upstream: &str) -> Result<Program> {
+    // Though I don't think anyone will use ALL UPPERCASE here...
+    let upstream = upstream.to_lowercase();
+    if upstream.starts_with("rsync://") || upstream.contains("::") {
+        Ok(Program::Rsync)
+    } else if upstream.starts_with("http://") || upstream.starts_with("https://") {
+        if upstream.ends_with(".git") {
+            Ok(Program::Git)
+        } else {
+            Ok(Program::Curl)
+        }
+    } else if upstream.starts_with("git://") {
+        Ok(Program::Git)
+    } else {
+        anyhow::bail!(
+            "Cannot detect program of upstream {upstream}. Please specify with --program."
+        )
+    }
+}
 
-    let mut config_file = None;
-    let mut error_msgs = Vec::new();
-    for config in config_paths {
-        match File::open(&config) {
-            Ok(file) => {
-                config_file = Some(file);
-                break;
-            }
-            Err(e) => {
-                error_msgs.push(format!("Tried: {}, got error: {e}", config.display()));
+/// Remove min and max if feasible, and take average.
+/// Failed samples (like stalled or failed to connect) are handled by `policy`.
+fn calculate_score(samples: &[Sample], policy: FailurePolicy) -> f64 {
+    let counted: Vec<_> = samples
+        .iter()
+        .filter_map(|sample| {
+            if sample.outcome.is_none_or(Outcome::counts) {
+                Some(sample.bandwidth)
+            } else if policy == FailurePolicy::Zero {
+                Some(0.0)
+            } else {
+                None
             }
-        }
+        })
+        .collect();
+    if counted.is_empty() {
+        return 0.0;
+    }
+    let mut sum = 0_f64;
+    let mut vmin = f64::MAX;
+    let mut vmax = f64::MIN;
+    for &bandwidth in &counted {
+        sum += bandwidth;
+        vmin = f64::min(vmin, bandwidth);
+        vmax = f64::max(vmax, bandwidth);
     }
-    let Some(mut config_file) = config_file else {
-        panic!("Cannot open config file. {}", error_msgs.join("\n"));
+    let score = if counted.len() >= 3 {
+        // Remove min and max
+        sum -= vmin + vmax;
+        sum / (counted.len() - 2) as f64
+    } else {
+        sum / counted.len() as f64
     };
-    let mut full_config: String = String::new();
-    config_file
-        .read_to_string(&mut full_config)
-        .expect("Cannot read config file");
-    let profile =
-        get_profile(&args, &full_config).expect("Cannot parse config file or profile not found");
-
-    let program = if let Some(program) = args.program {
-        program
+    if policy == FailurePolicy::Penalize {
+        score * counted.len() as f64 / samples.len() as f64
     } else {
-        // We need to detect by upstream
-
-        // Though I don't think anyone will use ALL UPPERCASE here...
-        let upstream = args.upstream.to_lowercase();
-        if upstream.starts_with("rsync://") || upstream.contains("::") {
-            Program::Rsync
-        } else if upstream.starts_with("http://") || upstream.starts_with("https://") {
-            if upstream.ends_with(".git") {
-                Program::Git
-            } else {
-                Program::Curl
-            }
-        } else if upstream.starts_with("git://") {
-            Program::Git
-        } else {
-            panic!("Cannot detect upstream program. Please specify with --program.")
+        score
+    }
+}
+
+/// Print results of targets as an aligned table, best first
+fn print_results(results: &[TargetResult], unit: Unit) {
+    const HEADER: [&str; 7] = [
+        "Rank", "Network", "Comment", "Result", "Min", "Max", "Passes",
+    ];
+    // Whether each column is aligned to the left
+    const LEFT: [bool; 7] = [false, true, true, false, false, false, false];
+    let rows: Vec<[String; 7]> = results
+        .iter()
+        .enumerate()
+        .map(|(i, result)| {
+            let bandwidths: Vec<_> = result.succeeded().map(|sample| sample.bandwidth).collect();
+            let stat = |f: fn(f64, f64) -> f64| {
+                bandwidths
+                    .iter()
+                    .copied()
+                    .reduce(f)
+                    .map_or_else(|| "-".to_string(), |value| unit.format(value))
+            };
+            [
+                (i + 1).to_string(),
+                result.network.clone(),
+                result.comment.clone(),
+                unit.format(result.score),
+                stat(f64::min),
+                stat(f64::max),
+                format!("{}/{}", result.successes(), result.samples.len()),
+            ]
+        })
+        .collect();
+    let mut widths = HEADER.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
         }
+    }
+    let print_row = |row: &[&str]| {
+        let cells: Vec<_> = row
+            .iter()
+            .zip(widths)
+            .zip(LEFT)
+            .map(|((cell, width), left)| {
+                if left {
+                    format!("{cell:width$}")
+                } else {
+                    format!("{cell:>width$}")
+                }
+            })
+            .collect();
+        println!("{}", cells.join("  ").trim_end());
     };
+    print_row(&HEADER);
+    for row in &rows {
+        print_row(&row.each_ref().map(String::as_str));
+    }
+}
 
-    let runner = get_runner(profile.format, &args, profile, program);
-    let uses = runner.uses();
+/// Print bandwidth of every (upstream, target) pair, with best bind per upstream
+/// and best pair overall
+fn print_matrix(records: &[Record], unit: Unit) {
+    println!("Matrix:");
+    for (i, record) in records.iter().enumerate() {
+        println!("  [{}] {}", i + 1, record.upstream);
+    }
+    // Keep targets in the order of first appearance
+    let mut targets: Vec<&TargetResult> = Vec::new();
+    for target in records.iter().flat_map(|record| &record.targets) {
+        if !targets.iter().any(|t| t.network == target.network) {
+            targets.push(target);
+        }
+    }
+    let labels: Vec<_> = targets
+        .iter()
+        .map(|target| format!("{} ({})", target.network, target.comment))
+        .collect();
+    let label_width = labels.iter().map(|l| l.chars().count()).max().unwrap_or(0);
+    print!("{:label_width$}", "");
+    for i in 0..records.len() {
+        print!(" {:>14}", format!("[{}]", i + 1));
+    }
+    println!();
+    for (target, label) in targets.iter().zip(&labels) {
+        print!("{label:label_width$}");
+        for record in records {
+            match record.targets.iter().find(|t| t.network == target.network) {
+                Some(result) => print!(" {:>14}", unit.format(result.score)),
+                None => print!(" {:>14}", "-"),
+            }
+        }
+        println!();
+    }
 
-    let mut results: Vec<Vec<_>> = Vec::new();
-    for pass in 0..args.pass {
-        println!("Pass {pass}:");
-        let mut results_pass: Vec<_> = Vec::new();
-        for target in uses {
-            if term.load(Ordering::SeqCst) {
-                println!("Terminated by user.");
-                // return instead of directly exit() so we can clean up tmp files
-                return;
+    println!("Best bind per upstream:");
+    for record in records {
+        if let Some(best) = record.best() {
+            println!(
+                "{}: {} ({}): {}",
+                record.upstream,
+                best.network,
+                best.comment,
+                unit.format(best.score)
+            );
+        }
+    }
+    if let Some((record, best)) = records
+        .iter()
+        .filter_map(|record| record.best().map(|best| (record, best)))
+        .max_by(|a, b| a.1.score.partial_cmp(&b.1.score).unwrap())
+    {
+        println!(
+            "Best pair overall: {} ({}) with {}: {}",
+            best.network,
+            best.comment,
+            record.upstream,
+            unit.format(best.score)
+        );
+    }
+}
+
+fn config_command(args: &Args, command: &ConfigCommand) -> Result<()> {
+    let config = config::load(args.config.as_ref())?;
+    match command {
+        ConfigCommand::Show => {
+            let profile = config.get_profile(&args.profile)?;
+            if let Some(file) = config.profile_file(&args.profile) {
+                println!("# Defined in {}", file.display());
             }
-            // create tmp file or directory
-            let tmp_file = if program != Program::Git {
-                create_tmp_file(args.tmp_dir.as_ref())
-            } else {
-                create_tmp_dir(args.tmp_dir.as_ref())
-            };
-            let mut proc = runner.run(&target.network, &tmp_file, &log);
-            let prog_status =
-                proc.wait_timeout(Duration::from_secs(args.timeout as u64), term.clone());
-            let status = prog_status.status;
-            let duration = prog_status.time;
-            let duration_seconds = duration.as_secs_f64();
-            let mut state_str = {
-                if duration_seconds > args.timeout as f64 {
-                    format!("✅ {} timeout as expected", get_program_name(program))
-                } else {
-                    match status.code() {
-                        Some(code) => match code {
-                            0 => "✅ OK".to_owned(),
-                            _ => format!(
-                                "❌ {} failed with code {}",
-                                get_program_name(program),
-                                code
-                            ),
-                        },
-                        None => format!("❌ {} killed by signal", get_program_name(program)),
+            let mut profiles = std::collections::BTreeMap::new();
+            profiles.insert(&args.profile, profile);
+            print!("{}", toml::to_string(&profiles)?);
+        }
+        ConfigCommand::Check => {
+            println!("Config files:");
+            for file in config.files() {
+                println!("  {}", file.display());
+            }
+            let names: Vec<_> = config.profile_names().collect();
+            let mut failed = 0;
+            for name in &names {
+                let file = config.profile_file(name).unwrap().display();
+                match config
+                    .get_profile(name)
+                    .and_then(|profile| format::validate(&profile).map(|()| profile))
+                {
+                    Ok(profile) => println!("✅ {name} ({file}): {} target(s)", profile.uses.len()),
+                    Err(e) => {
+                        println!("❌ {name} ({file}): {e:#}");
+                        failed += 1;
                     }
                 }
-            };
-            if term.load(Ordering::SeqCst) {
-                state_str += " (terminated by user)";
             }
-            // check file size
-            let size = if program == Program::Git {
-                tmp_file.metadata().unwrap().len()
-            } else {
-                fs_extra::dir::get_size(&tmp_file).unwrap()
-            };
-            let bandwidth = size as f64 / duration_seconds; // Bytes / Seconds
-            let bandwidth = bandwidth / 1024_f64; // KB/s
             println!(
-                "{} ({}): {} KB/s ({})",
-                target.network, target.comment, bandwidth, state_str
+                "Available profiles: {}",
+                names
+                    .iter()
+                    .map(|name| name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
             );
-            results_pass.push(bandwidth);
+            if failed > 0 {
+                anyhow::bail!("{failed} of {} profile(s) have errors", names.len());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ProfileInfo<'a> {
+    name: &'a str,
+    file: &'a Path,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<Format>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    docker: Option<String>,
+    targets: Vec<Target>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+fn profiles_command(args: &Args, json: bool) -> Result<()> {
+    let config = config::load(args.config.as_ref())?;
+    let mut infos = Vec::new();
+    for name in config.profile_names() {
+        let file = config.profile_file(name).unwrap();
+        let info = match config.get_profile(name) {
+            Ok(profile) => {
+                let docker = profile.format == Format::Docker;
+                let mut targets: Vec<_> = profile
+                    .uses
+                    .into_iter()
+                    .map(|(network, entry)| Target::new(network, entry))
+                    .collect();
+                targets.sort_by(|a, b| a.network.cmp(&b.network));
+                ProfileInfo {
+                    name,
+                    file,
+                    format: Some(profile.format),
+                    image: docker.then_some(profile.image),
+                    docker: docker.then_some(profile.docker),
+                    targets,
+                    error: None,
+                }
+            }
+            Err(e) => ProfileInfo {
+                name,
+                file,
+                format: None,
+                image: None,
+                docker: None,
+                targets: Vec::new(),
+                error: Some(format!("{e:#}")),
+            },
+        };
+        infos.push(info);
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&infos)?);
+        return Ok(());
+    }
+    for info in infos {
+        println!("{} ({})", info.name, info.file.display());
+        if let Some(error) = info.error {
+            println!("  ❌ {error}");
+            continue;
+        }
+        if let Some(format) = info.format {
+            println!("  format: {format}");
+        }
+        if let Some(image) = info.image {
+            println!("  image: {image}");
+        }
+        if let Some(docker) = info.docker {
+            println!("  docker: {docker}");
+        }
+        println!("  targets:");
+        for target in info.targets {
+            match target.tag {
+                Some(tag) => println!("    {} ({}) [tag: {tag}]", target.network, target.comment),
+                None => println!("    {} ({})", target.network, target.comment),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Results of testing all upstreams of a profile
+struct RunResult {
+    records: Vec<Record>,
+    regression: bool,
+}
+
+/// Flag set when SIGINT or SIGTERM is received
+fn register_term() -> Arc<AtomicBool> {
+    let term = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGINT, Arc::clone(&term)).expect("Register SIGINT handler failed");
+    signal_hook::flag::register(SIGTERM, Arc::clone(&term))
+        .expect("Register SIGTERM handler failed");
+    term
+}
+
+/// Test all upstreams of a profile, save results to history and check regression.
+/// Returns None if terminated by user.
+fn run_profile(
+    name: &str,
+    profile: &Profile,
+    settings: &Settings,
+    term: &Arc<AtomicBool>,
+) -> Result<Option<RunResult>> {
+    let log = File::create(&settings.log)
+        .with_context(|| format!("Cannot open log file {}", settings.log))?;
+
+    let multiple = settings.upstream.len() > 1;
+    let mut records = Vec::new();
+    let mut regression = false;
+    for upstream in &settings.upstream {
+        let program = settings
+            .program
+            .map_or_else(|| detect_program(upstream), Ok)?;
+        if multiple {
+            println!("Upstream {upstream} ({program}):");
+        }
+
+        let runner = get_runner(profile.format, settings, profile.clone(), program, upstream)?;
+        let uses = runner.uses();
+
+        let mut results: Vec<Vec<_>> = Vec::new();
+        for pass in 0..settings.pass {
+            println!("Pass {pass}:");
+            let Some(results_pass) = run_pass(runner.as_ref(), settings, program, &log, term)
+            else {
+                println!("Terminated by user.");
+                // return instead of directly exit() so we can clean up tmp files
+                return Ok(None);
+            };
+            results.push(results_pass);
+        }
+
+        let mut calculated_results: Vec<_> = Vec::new();
+        for (i, ip) in uses.iter().enumerate() {
+            let samples: Vec<_> = results.iter().map(|pass| pass[i].clone()).collect();
+            calculated_results.push(TargetResult {
+                network: ip.network.clone(),
+                comment: ip.comment.clone(),
+                score: calculate_score(&samples, settings.on_failure),
+                samples,
+            });
         }
-        results.push(results_pass);
-    }
-
-    let mut calculated_results: Vec<_> = Vec::new();
-    for (i, ip) in uses.iter().enumerate() {
-        let mut sum = 0_f64;
-        let mut vmin = f64::MAX;
-        let mut vmax = f64::MIN;
-        for pass in &results {
-            let bandwidth = pass[i];
-            sum += bandwidth;
-            vmin = f64::min(vmin, bandwidth);
-            vmax = f64::max(vmax, bandwidth);
+
+        println!("Final Results (remove min and max if feasible, and take average):");
+        calculated_results.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+        print_results(&calculated_results, settings.unit);
+        if settings.chart {
+            chart::print(&calculated_results, settings.unit);
+        }
+
+        let mut record = Record {
+            id: 0,
+            time: history::now(),
+            profile: name.to_string(),
+            upstream: upstream.clone(),
+            program,
+            pass: settings.pass,
+            timeout: settings.timeout,
+            targets: calculated_results,
+        };
+        if let Some(baseline) = settings.baseline {
+            let warnings =
+                history::check_regression(&record, baseline, settings.regression_threshold)?;
+            for warning in &warnings {
+                println!("⚠️ {warning}");
+            }
+            regression |= !warnings.is_empty();
         }
-        let res = if args.pass >= 3 {
-            // Remove min and max
-            sum -= vmin + vmax;
-            sum / (args.pass - 2) as f64
+        // Last run is loaded before saving this one, so that hooks get the saved record
+        let hooks = settings.hooks.on_complete.is_some() || settings.hooks.on_change.is_some();
+        let previous = if hooks {
+            history::last(&record).unwrap_or_else(|e| {
+                eprintln!("Cannot load last run from history for hooks: {e:#}");
+                None
+            })
         } else {
-            sum / args.pass as f64
+            None
         };
-        calculated_results.push((ip.network.clone(), ip.comment.clone(), res));
+        if !settings.no_history {
+            if let Err(e) = history::save(&mut record) {
+                eprintln!("Failed to save results to history: {e:#}");
+            }
+        }
+        if hooks {
+            hook::run(&settings.hooks, &record, previous.as_ref());
+        }
+        records.push(record);
+    }
+
+    if multiple {
+        print_matrix(&records, settings.unit);
+    }
+    if let Some(path) = &settings.report {
+        report::write(Path::new(path), &records, settings.unit)?;
+    }
+    if let Some(path) = &settings.write_best {
+        best::write(
+            Path::new(path),
+            settings.write_best_format,
+            settings.write_best_margin,
+            &records,
+        )?;
+    }
+    Ok(Some(RunResult {
+        records,
+        regression,
+    }))
+}
+
+fn speedtest(args: &Args) -> Result<ExitCode> {
+    let term = register_term();
+
+    let config = config::load(args.config.as_ref())?;
+    let profile = config.get_profile(&args.profile)?;
+    let settings = Settings::new(args, &args.profile, &profile)?;
+
+    let Some(result) = run_profile(&args.profile, &profile, &settings, &term)? else {
+        return Ok(ExitCode::from(EXIT_INTERRUPTED));
+    };
+
+    let targets: Vec<_> = result
+        .records
+        .iter()
+        .flat_map(|record| record.targets.iter().map(|t| t.failed(record.timeout)))
+        .collect();
+    let failed = targets.iter().filter(|failed| **failed).count();
+    if failed > 0 {
+        println!(
+            "❌ {failed} of {} target(s) failed in all passes",
+            targets.len()
+        );
+        if failed == targets.len() && args.fail_on != FailOn::None {
+            return Ok(ExitCode::from(EXIT_ALL_FAILED));
+        }
+        if args.fail_on == FailOn::Any {
+            return Ok(ExitCode::from(EXIT_SOME_FAILED));
+        }
+    }
+    if result.regression {
+        return Ok(ExitCode::from(EXIT_REGRESSION));
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn docker_cleanup(args: &Args, all: bool, docker: Option<&String>) -> Result<()> {
+    let mut commands = BTreeSet::new();
+    if let Some(docker) = docker {
+        commands.insert(docker.clone());
+    } else if let Ok(config) = config::load(args.config.as_ref()) {
+        commands.extend(
+            config
+                .profile_names()
+                .filter_map(|name| config.get_profile(name).ok())
+                .filter(|profile| profile.format == Format::Docker)
+                .map(|profile| profile.docker),
+        );
+    }
+    // Without config file or docker profiles, use the default command
+    if commands.is_empty() {
+        commands.insert("docker".to_string());
+    }
+    for docker in commands {
+        let removed = format::docker::cleanup(&docker, all)?;
+        println!("Removed {removed} container(s) with {docker}");
     }
+    Ok(())
+}
 
-    println!("Final Results (remove min and max if feasible, and take average):");
-    calculated_results.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap());
-    for (ip, comment, res) in calculated_results {
-        println!("{ip} ({comment}): {res} KB/s");
+fn run_command(args: &Args, command: &Command) -> Result<()> {
+    match command {
+        Command::Config { command } => config_command(args, command),
+        Command::Profiles { json } => profiles_command(args, *json),
+        Command::Daemon { listen } => daemon::run(args, listen.as_ref()),
+        Command::History { command } => match command {
+            HistoryCommand::List => history::list(),
+            HistoryCommand::Show { id } => history::show(*id),
+            HistoryCommand::Compare { id } => history::compare(*id),
+        },
+        Command::Docker { command } => match command {
+            DockerCommand::Cleanup { all, docker } => docker_cleanup(args, *all, docker.as_ref()),
+        },
+    }
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let result = match &args.command {
+        Some(command) => run_command(&args, command).map(|()| ExitCode::SUCCESS),
+        None => speedtest(&args),
+    };
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+
+    use super::*;
+
+    fn exited(code: i32) -> ExitStatus {
+        ExitStatus::from_raw(code << 8)
+    }
+
+    fn status(status: ExitStatus, timed_out: bool, progress: &[u64]) -> ProgramStatus {
+        ProgramStatus {
+            status,
+            time: Duration::from_secs(progress.len() as u64),
+            timed_out,
+            progress: progress.to_vec(),
+        }
+    }
+
+    fn classify_curl(status: &ProgramStatus, size: u64) -> Outcome {
+        classify(Program::Curl, status, size, false)
+    }
+
+    #[test]
+    fn classify_by_exit_code() {
+        let ok = status(exited(0), false, &[]);
+        assert_eq!(classify_curl(&ok, 100), Outcome::Completed);
+        let connect = status(exited(7), false, &[]);
+        assert_eq!(classify_curl(&connect, 0), Outcome::ConnectFailure);
+        let http_error = status(exited(22), false, &[]);
+        assert_eq!(classify_curl(&http_error, 0), Outcome::ProgramError);
+        // git exits with 128 for connect failures as well
+        let git = status(exited(128), false, &[]);
+        assert_eq!(
+            classify(Program::Git, &git, 0, false),
+            Outcome::ProgramError
+        );
+        let killed = status(ExitStatus::from_raw(libc::SIGKILL), false, &[]);
+        assert_eq!(classify_curl(&killed, 0), Outcome::Killed);
+    }
+
+    #[test]
+    fn interrupted_takes_precedence() {
+        let timed_out = status(ExitStatus::from_raw(libc::SIGTERM), true, &[10, 20]);
+        assert_eq!(
+            classify(Program::Curl, &timed_out, 30, true),
+            Outcome::Interrupted
+        );
+        let ok = status(exited(0), false, &[]);
+        assert_eq!(classify(Program::Curl, &ok, 30, true), Outcome::Interrupted);
+    }
+
+    #[test]
+    fn timeout_takes_precedence_over_exit_code() {
+        // Programs may exit with any code when stopped at timeout
+        let transferring = status(exited(0), true, &[10, 20]);
+        assert_eq!(
+            classify_curl(&transferring, 30),
+            Outcome::TimedOutTransferring
+        );
+        let stalled = status(exited(7), true, &[0, 0]);
+        assert_eq!(classify_curl(&stalled, 0), Outcome::Stalled);
+    }
+
+    #[test]
+    fn stall_window_with_few_samples() {
+        // With fewer than STALL_SAMPLES + 1 samples, any data counts as progress
+        let short = status(exited(0), true, &[100, 100, 100]);
+        assert_eq!(classify_curl(&short, 100), Outcome::TimedOutTransferring);
+        assert_eq!(classify_curl(&short, 0), Outcome::Stalled);
+        let none = status(exited(0), true, &[]);
+        assert_eq!(classify_curl(&none, 0), Outcome::Stalled);
+    }
+
+    #[test]
+    fn stall_window_with_enough_samples() {
+        // Compared with the sample STALL_SAMPLES + 1 before the end
+        let stalled = status(exited(0), true, &[10, 100, 100, 100, 100, 100, 100]);
+        assert_eq!(classify_curl(&stalled, 100), Outcome::Stalled);
+        let transferring = status(exited(0), true, &[10, 90, 100, 100, 100, 100, 100]);
+        assert_eq!(
+            classify_curl(&transferring, 101),
+            Outcome::TimedOutTransferring
+        );
+        // Data before the window does not count
+        let late_stall = status(exited(0), true, &[100, 100, 100, 100, 100, 100, 100, 100]);
+        assert_eq!(classify_curl(&late_stall, 100), Outcome::Stalled);
+    }
+
+    fn sample(bandwidth: f64, outcome: Option<Outcome>) -> Sample {
+        Sample {
+            bandwidth,
+            size: 0,
+            duration: 1.0,
+            code: None,
+            outcome,
+            retries: 0,
+            progress: Vec::new(),
+        }
+    }
+
+    fn samples(values: &[f64], failed: usize) -> Vec<Sample> {
+        values
+            .iter()
+            .map(|&bandwidth| sample(bandwidth, Some(Outcome::Completed)))
+            .chain((0..failed).map(|_| sample(1000.0, Some(Outcome::Stalled))))
+            .collect()
+    }
+
+    fn assert_score(samples: &[Sample], policy: FailurePolicy, expected: f64) {
+        let score = calculate_score(samples, policy);
+        assert!(
+            (score - expected).abs() < 1e-9,
+            "{policy:?}: {score} != {expected}"
+        );
+    }
+
+    #[test]
+    fn score_is_trimmed_mean() {
+        for policy in [
+            FailurePolicy::Zero,
+            FailurePolicy::Exclude,
+            FailurePolicy::Penalize,
+        ] {
+            assert_score(&samples(&[10.0, 20.0, 60.0], 0), policy, 20.0);
+            assert_score(&samples(&[10.0, 20.0], 0), policy, 15.0);
+            assert_score(&[], policy, 0.0);
+        }
+        // History from older versions has no outcome
+        let legacy = [sample(10.0, None), sample(20.0, None)];
+        assert_score(&legacy, FailurePolicy::Exclude, 15.0);
+    }
+
+    #[test]
+    fn score_with_one_failure_in_three_passes() {
+        let samples = samples(&[10.0, 20.0], 1);
+        // Failed pass counts as 0, and is trimmed as the min
+        assert_score(&samples, FailurePolicy::Zero, 10.0);
+        // Too few passes left to trim
+        assert_score(&samples, FailurePolicy::Exclude, 15.0);
+        assert_score(&samples, FailurePolicy::Penalize, 10.0);
+    }
+
+    #[test]
+    fn score_with_one_failure_in_four_passes() {
+        let samples = samples(&[10.0, 20.0, 60.0], 1);
+        assert_score(&samples, FailurePolicy::Zero, 15.0);
+        assert_score(&samples, FailurePolicy::Exclude, 20.0);
+        assert_score(&samples, FailurePolicy::Penalize, 15.0);
+    }
+
+    #[test]
+    fn score_with_all_failed() {
+        let samples = samples(&[], 3);
+        for policy in [
+            FailurePolicy::Zero,
+            FailurePolicy::Exclude,
+            FailurePolicy::Penalize,
+        ] {
+            assert_score(&samples, policy, 0.0);
+        }
     }
 }
@@ -0,0 +1,331 @@
+// Plain arithmetic reads better than mul_add for chart coordinates
+#![allow(clippy::suboptimal_flops)]
+/// Self-contained HTML report of results, with charts in inline SVG
+use std::{fmt::Write as _, fs, path::Path};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    format::PROGRESS_INTERVAL,
+    history::{self, Record, TargetResult},
+    unit::Unit,
+};
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em auto; max-width: 960px; color: #222; }
+table { border-collapse: collapse; margin: 1em 0; }
+th, td { border: 1px solid #ccc; padding: 4px 8px; }
+th { background: #f0f0f0; text-align: left; }
+td.num { text-align: right; font-variant-numeric: tabular-nums; }
+td.failed { color: #c00; }
+tr.best td { font-weight: bold; }
+section { margin-bottom: 3em; }
+svg { display: block; margin: 1em 0; }
+svg text { font-size: 12px; fill: #444; }
+";
+
+// Colors of passes in throughput charts
+const PALETTE: [&str; 6] = [
+    "#1f77b4", "#ff7f0e", "#2ca02c", "#d62728", "#9467bd", "#8c564b",
+];
+
+const WIDTH: f64 = 900.0;
+const LABEL_WIDTH: f64 = 240.0;
+const ROW_HEIGHT: f64 = 30.0;
+const TICKS: usize = 5;
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn label(target: &TargetResult) -> String {
+    escape(&format!("{} ({})", target.network, target.comment))
+}
+
+/// Quantile of sorted values, with linear interpolation
+fn quantile(sorted: &[f64], q: f64) -> f64 {
+    let pos = (sorted.len() - 1) as f64 * q;
+    let lower = pos.floor();
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let index = lower as usize;
+    match sorted.get(index + 1) {
+        Some(next) => sorted[index] + (next - sorted[index]) * (pos - lower),
+        None => sorted[index],
+    }
+}
+
+/// Vertical grid lines and labels of x axis from 0 to max, below the plot of `height`
+fn x_axis(
+    out: &mut String,
+    left: f64,
+    width: f64,
+    height: f64,
+    max: f64,
+    text: impl Fn(f64) -> String,
+) {
+    for i in 0..=TICKS {
+        let value = max * i as f64 / TICKS as f64;
+        let x = left + width * i as f64 / TICKS as f64;
+        let _ = write!(
+            out,
+            r##"<line x1="{x:.1}" y1="0" x2="{x:.1}" y2="{height:.1}" stroke="#eee"/><text x="{x:.1}" y="{:.1}" text-anchor="middle">{}</text>"##,
+            height + 15.0,
+            text(value)
+        );
+    }
+}
+
+fn ranking(out: &mut String, record: &Record, unit: Unit) {
+    out.push_str("<table><tr><th>Rank</th><th>Network</th><th>Comment</th><th>Result</th>");
+    for pass in 0..record.pass {
+        let _ = write!(out, "<th>Pass {}</th>", pass + 1);
+    }
+    out.push_str("<th>Succeeded</th></tr>\n");
+    for (i, target) in record.targets.iter().enumerate() {
+        let _ = write!(
+            out,
+            r#"<tr{}><td class="num">{}</td><td>{}</td><td>{}</td><td class="num">{}</td>"#,
+            if i == 0 { r#" class="best""# } else { "" },
+            i + 1,
+            escape(&target.network),
+            escape(&target.comment),
+            unit.format(target.score)
+        );
+        for sample in &target.samples {
+            let outcome = sample
+                .outcome
+                .map_or_else(String::new, |outcome| format!("{outcome:?}"));
+            let class = if sample.ok(record.timeout) {
+                "num"
+            } else {
+                "num failed"
+            };
+            let retries = if sample.retries > 0 {
+                format!(" (retried {})", sample.retries)
+            } else {
+                String::new()
+            };
+            let _ = write!(
+                out,
+                r#"<td class="{class}" title="{outcome}{retries}">{}</td>"#,
+                unit.format(sample.bandwidth)
+            );
+        }
+        let _ = writeln!(
+            out,
+            r#"<td class="num">{}/{}</td></tr>"#,
+            target.successes(),
+            target.samples.len()
+        );
+    }
+    out.push_str("</table>\n");
+}
+
+/// Box plot of succeeded samples of each target
+fn box_plot(out: &mut String, record: &Record, unit: Unit) {
+    let max = record
+        .targets
+        .iter()
+        .flat_map(TargetResult::succeeded)
+        .map(|sample| sample.bandwidth)
+        .fold(0.0, f64::max);
+    if max <= 0.0 {
+        out.push_str("<p>No succeeded pass.</p>\n");
+        return;
+    }
+    let plot_width = WIDTH - LABEL_WIDTH - 20.0;
+    let height = ROW_HEIGHT * record.targets.len() as f64;
+    let x = |value: f64| LABEL_WIDTH + value / max * plot_width;
+    let _ = writeln!(
+        out,
+        r#"<svg width="{WIDTH}" height="{:.0}" xmlns="http://www.w3.org/2000/svg">"#,
+        height + 25.0
+    );
+    x_axis(out, LABEL_WIDTH, plot_width, height, max, |v| {
+        unit.format(v)
+    });
+    for (i, target) in record.targets.iter().enumerate() {
+        let y = ROW_HEIGHT * i as f64;
+        let middle = y + ROW_HEIGHT / 2.0;
+        let _ = write!(
+            out,
+            r#"<text x="{:.1}" y="{:.1}" text-anchor="end">{}</text>"#,
+            LABEL_WIDTH - 8.0,
+            middle + 4.0,
+            label(target)
+        );
+        let mut values: Vec<_> = target.succeeded().map(|sample| sample.bandwidth).collect();
+        if values.is_empty() {
+            continue;
+        }
+        values.sort_by(f64::total_cmp);
+        let (min, q1, median, q3, max) = (
+            values[0],
+            quantile(&values, 0.25),
+            quantile(&values, 0.5),
+            quantile(&values, 0.75),
+            values[values.len() - 1],
+        );
+        let _ = write!(
+            out,
+            r##"<line x1="{:.1}" y1="{middle:.1}" x2="{:.1}" y2="{middle:.1}" stroke="#666"/><rect x="{:.1}" y="{:.1}" width="{:.1}" height="{:.1}" fill="#9ecae1" stroke="#3182bd"/><line x1="{:.1}" y1="{:.1}" x2="{:.1}" y2="{:.1}" stroke="#08519c" stroke-width="2"/>"##,
+            x(min),
+            x(max),
+            x(q1),
+            y + 6.0,
+            (x(q3) - x(q1)).max(1.0),
+            ROW_HEIGHT - 12.0,
+            x(median),
+            y + 6.0,
+            x(median),
+            y + ROW_HEIGHT - 6.0
+        );
+        for value in &values {
+            let _ = write!(
+                out,
+                r##"<circle cx="{:.1}" cy="{middle:.1}" r="2.5" fill="#333"><title>{}</title></circle>"##,
+                x(*value),
+                unit.format(*value)
+            );
+        }
+    }
+    out.push_str("</svg>\n");
+}
+
+/// Bandwidth (KB/s) in each progress interval of a sample
+fn throughput(progress: &[u64]) -> Vec<f64> {
+    let interval = PROGRESS_INTERVAL.as_secs_f64();
+    let mut last = 0;
+    progress
+        .iter()
+        .map(|&size| {
+            let delta = size.saturating_sub(last);
+            last = size;
+            delta as f64 / interval / 1024.0
+        })
+        .collect()
+}
+
+/// A line chart of throughput over time for each target, with a line for each pass
+fn throughput_charts(out: &mut String, record: &Record, unit: Unit) {
+    let series: Vec<Vec<Vec<f64>>> = record
+        .targets
+        .iter()
+        .map(|target| {
+            target
+                .samples
+                .iter()
+                .map(|sample| throughput(&sample.progress))
+                .collect()
+        })
+        .collect();
+    let max = series
+        .iter()
+        .flatten()
+        .flatten()
+        .copied()
+        .fold(0.0, f64::max);
+    let seconds = series.iter().flatten().map(Vec::len).max().unwrap_or(0) as f64
+        * PROGRESS_INTERVAL.as_secs_f64();
+    if max <= 0.0 || seconds <= 0.0 {
+        return;
+    }
+    out.push_str("<h3>Throughput over time</h3>\n");
+    let (left, plot_width, height) = (90.0, WIDTH - 110.0, 120.0);
+    for (target, passes) in record.targets.iter().zip(&series) {
+        let _ = writeln!(
+            out,
+            r#"<h4>{}</h4><svg width="{WIDTH}" height="{:.0}" xmlns="http://www.w3.org/2000/svg">"#,
+            label(target),
+            height + 25.0
+        );
+        x_axis(out, left, plot_width, height, seconds, |v| {
+            format!("{}s", (v * 10.0).round() / 10.0)
+        });
+        let _ = write!(
+            out,
+            r##"<text x="{:.1}" y="12" text-anchor="end">{}</text><text x="{:.1}" y="{height:.1}" text-anchor="end">0</text><line x1="{left}" y1="{height:.1}" x2="{:.1}" y2="{height:.1}" stroke="#999"/>"##,
+            left - 6.0,
+            unit.format(max),
+            left - 6.0,
+            left + plot_width
+        );
+        for (pass, values) in passes.iter().enumerate() {
+            let points: Vec<_> = values
+                .iter()
+                .enumerate()
+                .map(|(i, value)| {
+                    let t = (i + 1) as f64 * PROGRESS_INTERVAL.as_secs_f64();
+                    format!(
+                        "{:.1},{:.1}",
+                        left + t / seconds * plot_width,
+                        height - value / max * (height - 5.0)
+                    )
+                })
+                .collect();
+            let _ = write!(
+                out,
+                r#"<polyline points="{}" fill="none" stroke="{}" stroke-width="1.5"><title>Pass {}</title></polyline>"#,
+                points.join(" "),
+                PALETTE[pass % PALETTE.len()],
+                pass + 1
+            );
+        }
+        out.push_str("</svg>\n");
+    }
+    out.push_str("<p>Lines are colored by pass: ");
+    for pass in 0..record.pass {
+        let _ = write!(
+            out,
+            r#"<span style="color: {}">Pass {}</span> "#,
+            PALETTE[pass % PALETTE.len()],
+            pass + 1
+        );
+    }
+    out.push_str("</p>\n");
+}
+
+fn render(records: &[Record], unit: Unit) -> String {
+    let mut out = String::new();
+    let title = records.first().map_or_else(String::new, |record| {
+        format!("bestbind report: {}", escape(&record.profile))
+    });
+    let _ = writeln!(
+        out,
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{title}</title><style>{STYLE}</style></head><body>\n<h1>{title}</h1>\n<p>Generated by bestbind {} at {}</p>",
+        env!("CARGO_PKG_VERSION"),
+        history::format_time(history::now())
+    );
+    for record in records {
+        let _ = writeln!(
+            out,
+            "<section><h2>{}</h2>\n<table><tr><th>Profile</th><td>{}</td></tr><tr><th>Upstream</th><td>{}</td></tr><tr><th>Program</th><td>{}</td></tr><tr><th>Passes</th><td>{}</td></tr><tr><th>Timeout</th><td>{}s</td></tr><tr><th>Time</th><td>{}</td></tr></table>",
+            escape(&record.upstream),
+            escape(&record.profile),
+            escape(&record.upstream),
+            record.program,
+            record.pass,
+            record.timeout,
+            history::format_time(record.time)
+        );
+        out.push_str("<h3>Ranking</h3>\n");
+        ranking(&mut out, record, unit);
+        out.push_str("<h3>Distribution of passes</h3>\n");
+        box_plot(&mut out, record, unit);
+        throughput_charts(&mut out, record, unit);
+        out.push_str("</section>\n");
+    }
+    out.push_str("</body></html>\n");
+    out
+}
+
+/// Write HTML report of records to path
+pub fn write(path: &Path, records: &[Record], unit: Unit) -> Result<()> {
+    fs::write(path, render(records, unit))
+        .with_context(|| format!("Cannot write report {}", path.display()))?;
+    println!("Report written to {}", path.display());
+    Ok(())
+}
@@ -0,0 +1,185 @@
+/// Distribute targets of one pass to parallel workers
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{Condvar, Mutex, PoisonError},
+};
+
+use crate::Target;
+
+struct State {
+    pending: VecDeque<usize>,
+    busy_tags: HashSet<String>,
+}
+
+pub struct Scheduler<'a> {
+    targets: &'a [Target],
+    // Targets sharing a tag are on the same uplink, and testing them at the same time
+    // makes them compete for bandwidth. When set, only one of them runs at a time.
+    distinct_tags: bool,
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+impl<'a> Scheduler<'a> {
+    pub fn new(targets: &'a [Target], distinct_tags: bool) -> Self {
+        Self {
+            targets,
+            distinct_tags,
+            state: Mutex::new(State {
+                pending: (0..targets.len()).collect(),
+                busy_tags: HashSet::new(),
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    fn runnable(&self, state: &State, index: usize) -> bool {
+        if !self.distinct_tags {
+            return true;
+        }
+        self.targets[index]
+            .tag
+            .as_ref()
+            .is_none_or(|tag| !state.busy_tags.contains(tag))
+    }
+
+    /// Take the next target to test, blocking while all pending targets
+    /// share a tag with a running one. Returns None when nothing is left.
+    pub fn next(&self) -> Option<Ticket<'_, 'a>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.pending.is_empty() {
+                return None;
+            }
+            if let Some(pos) =
+                (0..state.pending.len()).find(|&pos| self.runnable(&state, state.pending[pos]))
+            {
+                let index = state.pending.remove(pos).unwrap();
+                if self.distinct_tags {
+                    if let Some(tag) = &self.targets[index].tag {
+                        state.busy_tags.insert(tag.clone());
+                    }
+                }
+                return Some(Ticket {
+                    scheduler: self,
+                    index,
+                });
+            }
+            state = self.cond.wait(state).unwrap();
+        }
+    }
+
+    /// Mark a target returned by `next()` as finished
+    fn done(&self, index: usize) {
+        // Also called when unwinding from a panicking test
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(tag) = &self.targets[index].tag {
+            state.busy_tags.remove(tag);
+        }
+        drop(state);
+        self.cond.notify_all();
+    }
+}
+
+/// A target taken by `Scheduler::next()`, which is finished when dropped,
+/// so that its tag is released even if testing it panics
+pub struct Ticket<'s, 'a> {
+    scheduler: &'s Scheduler<'a>,
+    pub index: usize,
+}
+
+impl Drop for Ticket<'_, '_> {
+    fn drop(&mut self) {
+        self.scheduler.done(self.index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, time::Duration};
+
+    use super::*;
+
+    fn next(scheduler: &Scheduler) -> Option<usize> {
+        // Dropping the ticket finishes the target at once
+        scheduler.next().map(|ticket| ticket.index)
+    }
+
+    fn target(network: &str, tag: Option<&str>) -> Target {
+        Target {
+            network: network.to_string(),
+            comment: String::new(),
+            tag: tag.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn all_targets_in_order_without_distinct_tags() {
+        let targets = [
+            target("a", Some("x")),
+            target("b", Some("x")),
+            target("c", None),
+        ];
+        let scheduler = Scheduler::new(&targets, false);
+        let a = scheduler.next().unwrap();
+        let b = scheduler.next().unwrap();
+        let c = scheduler.next().unwrap();
+        assert_eq!((a.index, b.index, c.index), (0, 1, 2));
+        assert!(scheduler.next().is_none());
+    }
+
+    #[test]
+    fn skips_targets_with_busy_tags() {
+        let targets = [
+            target("a", Some("x")),
+            target("b", Some("x")),
+            target("c", None),
+        ];
+        let scheduler = Scheduler::new(&targets, true);
+        let a = scheduler.next().unwrap();
+        assert_eq!(a.index, 0);
+        // b shares the tag of running a
+        assert_eq!(next(&scheduler), Some(2));
+        drop(a);
+        assert_eq!(next(&scheduler), Some(1));
+        assert_eq!(next(&scheduler), None);
+    }
+
+    #[test]
+    fn blocks_until_tag_is_released() {
+        let targets = [target("a", Some("x")), target("b", Some("x"))];
+        let scheduler = Scheduler::new(&targets, true);
+        let a = scheduler.next().unwrap();
+        let scheduler = &scheduler;
+        std::thread::scope(|scope| {
+            let (sender, receiver) = mpsc::channel();
+            scope.spawn(move || sender.send(next(scheduler)).unwrap());
+            assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+            drop(a);
+            assert_eq!(
+                receiver.recv_timeout(Duration::from_secs(5)).unwrap(),
+                Some(1)
+            );
+        });
+    }
+
+    #[test]
+    fn tag_is_released_when_testing_panics() {
+        let targets = [target("a", Some("x")), target("b", Some("x"))];
+        let scheduler = Scheduler::new(&targets, true);
+        let scheduler = &scheduler;
+        std::thread::scope(|scope| {
+            let panicked = scope.spawn(move || {
+                let _ticket = scheduler.next().unwrap();
+                panic!("test panics");
+            });
+            assert!(panicked.join().is_err());
+            let (sender, receiver) = mpsc::channel();
+            scope.spawn(move || sender.send(next(scheduler)).unwrap());
+            assert_eq!(
+                receiver.recv_timeout(Duration::from_secs(5)).unwrap(),
+                Some(1)
+            );
+        });
+    }
+}
@@ -0,0 +1,254 @@
+/// A small HTTP server exposing latest results in daemon mode
+use std::{
+    fmt::Write as _,
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener},
+    os::unix::{fs::FileTypeExt, net::UnixListener},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    daemon::Latest,
+    history::{Record, TargetResult},
+};
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    const fn new(status: &'static str, content_type: &'static str, body: String) -> Self {
+        Self {
+            status,
+            content_type,
+            body,
+        }
+    }
+
+    fn text(status: &'static str, body: &str) -> Self {
+        Self::new(status, "text/plain; charset=utf-8", format!("{body}\n"))
+    }
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => result.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+                match u8::from_str_radix(hex, 16) {
+                    Ok(byte) => {
+                        result.push(byte);
+                        i += 2;
+                    }
+                    Err(_) => result.push(b'%'),
+                }
+            }
+            byte => result.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+fn query_param(query: &str, key: &str) -> Option<String> {
+    query.split('&').find_map(|pair| {
+        let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+        (percent_decode(k) == key).then(|| percent_decode(v))
+    })
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn metrics(latest: &Latest) -> String {
+    let mut out = String::new();
+    out +=
+        "# HELP bestbind_bandwidth_bytes_per_second Final bandwidth of target in the latest run.\n";
+    out += "# TYPE bestbind_bandwidth_bytes_per_second gauge\n";
+    for record in &latest.records {
+        for target in &record.targets {
+            let _ = writeln!(
+                out,
+                "bestbind_bandwidth_bytes_per_second{{profile=\"{}\",upstream=\"{}\",network=\"{}\",comment=\"{}\"}} {}",
+                escape_label(&record.profile),
+                escape_label(&record.upstream),
+                escape_label(&target.network),
+                escape_label(&target.comment),
+                target.score * 1024.0
+            );
+        }
+    }
+    out += "# HELP bestbind_best Whether target is the best bind in the latest run.\n";
+    out += "# TYPE bestbind_best gauge\n";
+    for record in &latest.records {
+        let best = winner(record).map(|best| best.network.as_str());
+        for target in &record.targets {
+            let _ = writeln!(
+                out,
+                "bestbind_best{{profile=\"{}\",upstream=\"{}\",network=\"{}\"}} {}",
+                escape_label(&record.profile),
+                escape_label(&record.upstream),
+                escape_label(&target.network),
+                u8::from(best == Some(target.network.as_str()))
+            );
+        }
+    }
+    out += "# HELP bestbind_last_run_timestamp_seconds Time of the latest run.\n";
+    out += "# TYPE bestbind_last_run_timestamp_seconds gauge\n";
+    for record in &latest.records {
+        let _ = writeln!(
+            out,
+            "bestbind_last_run_timestamp_seconds{{profile=\"{}\",upstream=\"{}\"}} {}",
+            escape_label(&record.profile),
+            escape_label(&record.upstream),
+            record.time
+        );
+    }
+    out
+}
+
+/// Best target of a record, None if all targets failed, like in `best::write`
+fn winner(record: &Record) -> Option<&TargetResult> {
+    record.best().filter(|best| best.score > 0.0)
+}
+
+fn best(latest: &Latest, query: &str) -> Response {
+    let Some(profile) = query_param(query, "profile") else {
+        return Response::text("400 Bad Request", "Missing profile parameter");
+    };
+    let upstream = query_param(query, "upstream");
+    let records: Vec<_> = latest
+        .records
+        .iter()
+        .filter(|record| {
+            record.profile == profile && upstream.as_ref().is_none_or(|u| record.upstream == *u)
+        })
+        .collect();
+    match records.as_slice() {
+        [] => Response::text("404 Not Found", "No result for this profile and upstream"),
+        [record] => match (winner(record), record.best()) {
+            (Some(best), _) => Response::text("200 OK", &best.network),
+            (None, Some(_)) => Response::text(
+                "503 Service Unavailable",
+                "All targets failed in latest run",
+            ),
+            (None, None) => Response::text("404 Not Found", "No target in result"),
+        },
+        _ => Response::text(
+            "400 Bad Request",
+            "Profile has multiple upstreams, please specify upstream parameter",
+        ),
+    }
+}
+
+fn route(latest: &Mutex<Latest>, method: &str, target: &str) -> Response {
+    if method != "GET" {
+        return Response::text("405 Method Not Allowed", "Only GET is supported");
+    }
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let latest = latest.lock().unwrap();
+    match path {
+        "/" | "/results" => match serde_json::to_string_pretty(&*latest) {
+            Ok(json) => Response::new("200 OK", "application/json", json),
+            Err(e) => Response::text("500 Internal Server Error", &e.to_string()),
+        },
+        "/metrics" => Response::new("200 OK", "text/plain; version=0.0.4", metrics(&latest)),
+        "/best" => best(&latest, query),
+        _ => Response::text("404 Not Found", "Not found"),
+    }
+}
+
+fn handle<S: Read + Write>(stream: S, latest: &Mutex<Latest>) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip headers, as none of them matters here
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+    }
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => route(latest, method, target),
+        _ => Response::text("400 Bad Request", "Bad request"),
+    };
+    let mut stream = reader.into_inner();
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.content_type,
+        response.body.len(),
+        response.body
+    )?;
+    stream.flush()
+}
+
+/// Start serving in background. `listen` is an address like 127.0.0.1:9091,
+/// or a Unix socket path prefixed with "unix:".
+pub fn spawn(listen: &str, latest: Arc<Mutex<Latest>>) -> Result<()> {
+    // A client should not block the server for long
+    let timeout = Some(Duration::from_secs(5));
+    if let Some(path) = listen.strip_prefix("unix:") {
+        let path = Path::new(path);
+        // Remove socket left by last run, but never other files
+        match path.symlink_metadata() {
+            Ok(metadata) if metadata.file_type().is_socket() => {
+                std::fs::remove_file(path)
+                    .with_context(|| format!("Cannot remove old socket {}", path.display()))?;
+            }
+            Ok(_) => anyhow::bail!("{} exists and is not a socket", path.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("Cannot access {}", path.display()));
+            }
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("Cannot listen on {}", path.display()))?;
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let _ = stream.set_read_timeout(timeout);
+                let _ = stream.set_write_timeout(timeout);
+                if let Err(e) = handle(stream, &latest) {
+                    eprintln!("Failed to handle HTTP request: {e}");
+                }
+            }
+        });
+    } else {
+        let addr: SocketAddr = listen
+            .parse()
+            .with_context(|| format!("Invalid listen address {listen}"))?;
+        if !addr.ip().is_loopback() {
+            eprintln!("Warning: status server on {addr} is reachable from other hosts, without any authentication");
+        }
+        let listener =
+            TcpListener::bind(addr).with_context(|| format!("Cannot listen on {addr}"))?;
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let _ = stream.set_read_timeout(timeout);
+                let _ = stream.set_write_timeout(timeout);
+                if let Err(e) = handle(stream, &latest) {
+                    eprintln!("Failed to handle HTTP request: {e}");
+                }
+            }
+        });
+    }
+    println!("Serving latest results on {listen}");
+    Ok(())
+}
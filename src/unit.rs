@@ -0,0 +1,35 @@
+/// Units to show bandwidth in. Bandwidth is always stored in KB/s.
+use clap::ValueEnum;
+
+#[derive(Debug, ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    #[value(name = "KB/s")]
+    KBps,
+    #[value(name = "MB/s")]
+    MBps,
+    #[value(name = "Mbit/s")]
+    Mbps,
+    /// KB/s, MB/s or GB/s, whichever fits the value
+    Auto,
+}
+
+impl Unit {
+    /// Format bandwidth in KB/s, with unit suffix
+    pub fn format(self, kbps: f64) -> String {
+        let (value, unit) = match self {
+            Self::KBps => (kbps, "KB/s"),
+            Self::MBps => (kbps / 1024.0, "MB/s"),
+            Self::Mbps => (kbps * 1024.0 * 8.0 / 1_000_000.0, "Mbit/s"),
+            Self::Auto => {
+                if kbps >= 1024.0 * 1024.0 {
+                    (kbps / 1024.0 / 1024.0, "GB/s")
+                } else if kbps >= 1024.0 {
+                    (kbps / 1024.0, "MB/s")
+                } else {
+                    (kbps, "KB/s")
+                }
+            }
+        };
+        format!("{value:.2} {unit}")
+    }
+}